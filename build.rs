@@ -0,0 +1,266 @@
+//! Converts the images in `images/` into compressed 1-bit frames.
+//!
+//! Every PNG or BMP directly in `images/` becomes a single frame animation,
+//! and every folder becomes an animation with one frame per image, in file
+//! name order. The constant is named after the file or folder, so
+//! `images/bongo_tap/` turns into `images::BONGO_TAP`. A folder may hold a
+//! `frame_ms` file with the frame period, otherwise `DEFAULT_FRAME_MS` is used.
+//!
+//! Pixels are thresholded on luma (and alpha for PNGs), then run length
+//! encoded row by row, see `src/animation.rs` for the format.
+//!
+//! Also sets `GIT_HASH` and `BUILD_DATE` for the boot splash.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const IMAGES_DIR: &str = "images";
+const DEFAULT_FRAME_MS: u32 = 100;
+const MAX_RUN: usize = 128;
+
+struct Bitmap {
+    width: u32,
+    height: u32,
+    // Row major, true is a lit pixel
+    pixels: Vec<bool>,
+}
+
+fn main() {
+    build_info();
+    images();
+}
+
+fn build_info() {
+    // Pick up new commits and checkouts
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    println!("cargo:rustc-env=BUILD_DATE={:04}-{:02}-{:02}", year, month, day);
+}
+
+/// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's
+/// `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn images() {
+    println!("cargo:rerun-if-changed={}", IMAGES_DIR);
+
+    let mut out = String::new();
+    let mut names = Vec::new();
+
+    for path in sorted_entries(Path::new(IMAGES_DIR)) {
+        let name = const_name(&path);
+
+        let (frames, frame_ms) = if path.is_dir() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            let frames: Vec<Bitmap> = sorted_entries(&path)
+                .iter()
+                .filter_map(|p| load_image(p))
+                .collect();
+            let frame_ms = fs::read_to_string(path.join("frame_ms"))
+                .ok()
+                .map(|s| s.trim().parse().expect("frame_ms must be a number"))
+                .unwrap_or(DEFAULT_FRAME_MS);
+            (frames, frame_ms)
+        } else {
+            match load_image(&path) {
+                Some(frame) => (vec![frame], DEFAULT_FRAME_MS),
+                None => continue,
+            }
+        };
+
+        if frames.is_empty() {
+            continue;
+        }
+        let (width, height) = (frames[0].width, frames[0].height);
+        for frame in frames.iter() {
+            if frame.width != width || frame.height != height {
+                panic!("{}: all frames must be the same size", path.display());
+            }
+        }
+
+        writeln!(out, "pub static {}: Animation = Animation {{", name).unwrap();
+        writeln!(out, "    name: {:?},", name.to_lowercase()).unwrap();
+        writeln!(out, "    size: Size::new({}, {}),", width, height).unwrap();
+        writeln!(out, "    frame_us: {},", frame_ms as u64 * 1000).unwrap();
+        writeln!(out, "    frames: &[").unwrap();
+        for frame in frames.iter() {
+            writeln!(out, "        Frame::new(&{:?}),", encode(frame)).unwrap();
+        }
+        writeln!(out, "    ],").unwrap();
+        writeln!(out, "}};").unwrap();
+        names.push(name);
+    }
+
+    writeln!(out, "/// Every animation, in file name order").unwrap();
+    writeln!(out, "pub static ALL: [&Animation; {}] = [", names.len()).unwrap();
+    for name in names.iter() {
+        writeln!(out, "    &{},", name).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("images.rs"), out).unwrap();
+}
+
+fn sorted_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|e| e.unwrap().path())
+        .collect();
+    entries.sort();
+    return entries;
+}
+
+fn const_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap()
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+fn load_image(path: &Path) -> Option<Bitmap> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    let bytes = fs::read(path).unwrap();
+    let bitmap = match extension.as_str() {
+        "bmp" => decode_bmp(&bytes),
+        "png" => decode_png(&bytes),
+        _ => return None,
+    };
+    Some(bitmap.unwrap_or_else(|e| panic!("{}: {}", path.display(), e)))
+}
+
+fn lit(r: u8, g: u8, b: u8) -> bool {
+    let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+    luma >= 128
+}
+
+fn decode_png(bytes: &[u8]) -> Result<Bitmap, String> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+
+    let channels = info.color_type.samples();
+    let pixels = buf[..info.buffer_size()]
+        .chunks(channels)
+        .map(|p| match info.color_type {
+            png::ColorType::Grayscale => p[0] >= 128,
+            png::ColorType::GrayscaleAlpha => p[0] >= 128 && p[1] >= 128,
+            png::ColorType::Rgb => lit(p[0], p[1], p[2]),
+            png::ColorType::Rgba => lit(p[0], p[1], p[2]) && p[3] >= 128,
+            // Expanded away by the decoder
+            png::ColorType::Indexed => unreachable!(),
+        })
+        .collect();
+
+    Ok(Bitmap {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+/// Uncompressed BMPs only, at 1, 4, 8, 24 or 32 bits per pixel
+fn decode_bmp(bytes: &[u8]) -> Result<Bitmap, String> {
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+    if bytes.len() < 54 || &bytes[..2] != b"BM" {
+        return Err("not a BMP".into());
+    }
+    let data_offset = u32_at(10) as usize;
+    let header_len = u32_at(14) as usize;
+    let width = u32_at(18) as i32;
+    let height = u32_at(22) as i32;
+    let bpp = u16_at(28) as usize;
+    let compression = u32_at(30);
+    let colors_used = u32_at(46) as usize;
+
+    if compression != 0 && !(compression == 3 && bpp == 32) {
+        return Err("compressed BMPs are not supported".into());
+    }
+
+    let palette_offset = 14 + header_len;
+    let palette_len = if colors_used == 0 && bpp <= 8 { 1 << bpp } else { colors_used };
+    let palette: Vec<bool> = (0..palette_len)
+        .map(|i| {
+            let p = &bytes[palette_offset + i * 4..];
+            lit(p[2], p[1], p[0])
+        })
+        .collect();
+
+    // Rows are stored bottom up unless the height is negative
+    let (width, top_down) = (width as usize, height < 0);
+    let height = height.unsigned_abs() as usize;
+    let stride = (width * bpp + 31) / 32 * 4;
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row_y = if top_down { y } else { height - 1 - y };
+        let row = &bytes[data_offset + row_y * stride..];
+        for x in 0..width {
+            let pixel = match bpp {
+                1 => palette[(row[x / 8] >> (7 - x % 8) & 1) as usize],
+                4 => palette[(row[x / 2] >> (4 - x % 2 * 4) & 0xf) as usize],
+                8 => palette[row[x] as usize],
+                24 => lit(row[x * 3 + 2], row[x * 3 + 1], row[x * 3]),
+                32 => lit(row[x * 4 + 2], row[x * 4 + 1], row[x * 4]),
+                _ => return Err(format!("{} bits per pixel is not supported", bpp)),
+            };
+            pixels.push(pixel);
+        }
+    }
+
+    Ok(Bitmap {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
+
+/// Each byte is a run of pixels: the top bit is the colour, the low 7 bits
+/// are the run length minus one.
+fn encode(bitmap: &Bitmap) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pixels = bitmap.pixels.iter().peekable();
+
+    while let Some(&color) = pixels.next() {
+        let mut len = 1;
+        while len < MAX_RUN && pixels.peek() == Some(&&color) {
+            pixels.next();
+            len += 1;
+        }
+        out.push((color as u8) << 7 | (len - 1) as u8);
+    }
+    return out;
+}
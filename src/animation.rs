@@ -0,0 +1,149 @@
+//! 1-bit animations generated from `images/` by `build.rs`.
+//!
+//! Frames are stored run length encoded, row by row: each byte is a run of
+//! pixels of one colour, the top bit being the colour and the low 7 bits the
+//! run length minus one. Frames are decoded on the fly while drawing, only
+//! lit pixels are drawn.
+
+use embedded_graphics::{
+    image::ImageDrawable,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::Rectangle,
+};
+
+pub struct Frame {
+    data: &'static [u8],
+}
+
+impl Frame {
+    pub const fn new(data: &'static [u8]) -> Self {
+        Self { data }
+    }
+}
+
+pub struct Animation {
+    pub name: &'static str,
+    pub size: Size,
+    /// Frame period, from the `frame_ms` file in the animation's folder
+    pub frame_us: u64,
+    pub frames: &'static [Frame],
+}
+
+impl Animation {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Frame `index`, wrapping around, for use with `Image`
+    pub fn frame(&'static self, index: usize) -> FrameImage {
+        FrameImage {
+            size: self.size,
+            data: self.frames[index % self.frames.len()].data,
+        }
+    }
+
+    /// How long one pass through all the frames takes
+    pub fn duration_us(&self) -> u64 {
+        self.frame_us * self.frames.len() as u64
+    }
+}
+
+/// One decodable frame of an animation
+#[derive(Clone, Copy)]
+pub struct FrameImage {
+    size: Size,
+    data: &'static [u8],
+}
+
+impl FrameImage {
+    fn lit_pixels(&self) -> impl Iterator<Item = Pixel<BinaryColor>> {
+        let width = self.size.width;
+        self.data
+            .iter()
+            .scan(0, |pos: &mut u32, &run| {
+                let start = *pos;
+                *pos += (run & 0x7f) as u32 + 1;
+                Some((start, *pos, run & 0x80 != 0))
+            })
+            .filter(|(_, _, lit)| *lit)
+            .flat_map(move |(start, end, _)| {
+                (start..end).map(move |i| {
+                    Pixel(Point::new((i % width) as i32, (i / width) as i32), BinaryColor::On)
+                })
+            })
+    }
+}
+
+impl OriginDimensions for FrameImage {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl ImageDrawable for FrameImage {
+    type Color = BinaryColor;
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        target.draw_iter(self.lit_pixels())
+    }
+
+    fn draw_sub_image<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut clipped = target.clipped(&Rectangle::new(Point::zero(), area.size));
+        self.draw(&mut clipped.translated(-area.top_left))
+    }
+}
+
+/// Steps through the frames of an animation
+pub struct AnimationPlayer {
+    animation: &'static Animation,
+    index: usize,
+    last_frame_us: u64,
+}
+
+impl AnimationPlayer {
+    pub fn new(animation: &'static Animation) -> Self {
+        Self {
+            animation,
+            index: 0,
+            last_frame_us: 0,
+        }
+    }
+
+    pub fn animation(&self) -> &'static Animation {
+        self.animation
+    }
+
+    /// Switch animation, starting from its first frame
+    pub fn play(&mut self, animation: &'static Animation, now_us: u64) {
+        self.animation = animation;
+        self.index = 0;
+        self.last_frame_us = now_us;
+    }
+
+    pub fn frame(&self) -> FrameImage {
+        self.animation.frame(self.index)
+    }
+
+    /// Go to the next frame straight away
+    pub fn step(&mut self, now_us: u64) {
+        self.index = (self.index + 1) % self.animation.len();
+        self.last_frame_us = now_us;
+    }
+
+    /// Go to the next frame if `frame_us` has passed since the last one.
+    /// Returns true if the frame changed.
+    pub fn tick(&mut self, now_us: u64, frame_us: u64) -> bool {
+        if now_us - self.last_frame_us < frame_us || self.animation.len() < 2 {
+            return false;
+        }
+        self.step(now_us);
+        true
+    }
+}
@@ -0,0 +1,124 @@
+//! OLED burn-in protection.
+//!
+//! Tracks how long the display has gone without a keypress. Once idle, the
+//! image is shifted around by a couple of pixels periodically, then the panel
+//! is dimmed, and finally turned off. Any keypress brings it straight back.
+
+use embedded_graphics::prelude::Point;
+
+// Offsets cycled through while shifting, a couple of pixels around the origin
+const SHIFT_OFFSETS: [Point; 8] = [
+    Point::new(2, 0),
+    Point::new(2, 2),
+    Point::new(0, 2),
+    Point::new(-2, 2),
+    Point::new(-2, 0),
+    Point::new(-2, -2),
+    Point::new(0, -2),
+    Point::new(2, -2),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlankingConfig {
+    /// Idle time before the image starts shifting
+    pub shift_after_us: u64,
+    /// Time between shifts
+    pub shift_period_us: u64,
+    /// Idle time before the panel is dimmed
+    pub dim_after_us: u64,
+    /// Idle time before the panel is turned off
+    pub off_after_us: u64,
+}
+
+impl Default for BlankingConfig {
+    fn default() -> Self {
+        Self {
+            shift_after_us: 60_000_000,
+            shift_period_us: 30_000_000,
+            dim_after_us: 5 * 60_000_000,
+            off_after_us: 15 * 60_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlankingState {
+    Active,
+    Shifting,
+    Dimmed,
+    Off,
+}
+
+pub struct Blanking {
+    config: BlankingConfig,
+    state: BlankingState,
+    last_activity_us: u64,
+    last_shift_us: u64,
+    shift_index: Option<usize>,
+}
+
+impl Blanking {
+    pub fn new(config: BlankingConfig) -> Self {
+        Self {
+            config,
+            state: BlankingState::Active,
+            last_activity_us: 0,
+            last_shift_us: 0,
+            shift_index: None,
+        }
+    }
+
+    pub fn state(&self) -> BlankingState {
+        self.state
+    }
+
+    /// Offset to draw the image at
+    pub fn offset(&self) -> Point {
+        self.shift_index.map_or(Point::zero(), |i| SHIFT_OFFSETS[i])
+    }
+
+    /// Record activity. Returns true if the display was blanked in any way
+    /// and needs restoring.
+    pub fn activity(&mut self, now_us: u64) -> bool {
+        self.last_activity_us = now_us;
+
+        if self.state == BlankingState::Active {
+            return false;
+        }
+        self.state = BlankingState::Active;
+        self.shift_index = None;
+        true
+    }
+
+    /// Advance the state machine. Returns true if the state or the offset
+    /// changed.
+    pub fn tick(&mut self, now_us: u64) -> bool {
+        let idle_us = now_us.saturating_sub(self.last_activity_us);
+
+        let state = if idle_us >= self.config.off_after_us {
+            BlankingState::Off
+        } else if idle_us >= self.config.dim_after_us {
+            BlankingState::Dimmed
+        } else if idle_us >= self.config.shift_after_us {
+            BlankingState::Shifting
+        } else {
+            BlankingState::Active
+        };
+
+        let mut changed = state != self.state;
+        self.state = state;
+
+        // Keep shifting while dimmed too
+        let shifting = state == BlankingState::Shifting || state == BlankingState::Dimmed;
+        if shifting
+            && (self.shift_index.is_none()
+                || now_us - self.last_shift_us >= self.config.shift_period_us)
+        {
+            self.shift_index = Some(self.shift_index.map_or(0, |i| (i + 1) % SHIFT_OFFSETS.len()));
+            self.last_shift_us = now_us;
+            changed = true;
+        }
+
+        return changed;
+    }
+}
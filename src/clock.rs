@@ -29,3 +29,111 @@ impl<'a> Clock for PicoClock<'a> {
         ))
     }
 }
+
+impl<'a> PicoClock<'a> {
+    /// Microseconds since boot
+    pub fn now_us(&self) -> u64 {
+        self.try_now().unwrap().duration_since_epoch().integer()
+    }
+}
+
+/// Latest time the host may set, the end of 9999
+pub const MAX_UNIX_SECS: i64 = 253_402_300_799;
+/// UTC offsets run from -12:00 to +14:00, this allows a little either side
+pub const MAX_UTC_OFFSET_SECS: i32 = 14 * 3600;
+
+/// Local date and time
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 is Monday
+    pub weekday: u8,
+}
+
+impl DateTime {
+    const WEEKDAYS: [&'static str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+    /// From seconds since 1970-01-01, using Howard Hinnant's `civil_from_days`
+    pub fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86400);
+        let secs_of_day = secs.rem_euclid(86400);
+
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            // Only out of range a long way past MAX_UNIX_SECS
+            year: year.clamp(0, 9999) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 3).rem_euclid(7) as u8,
+        }
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        Self::WEEKDAYS[self.weekday as usize]
+    }
+}
+
+/// Wall clock time, set by the host and kept running from the timer
+pub struct WallClock {
+    // Unix time in microseconds at boot, once set
+    unix_at_boot_us: Option<i64>,
+    utc_offset_secs: i32,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self {
+            unix_at_boot_us: None,
+            utc_offset_secs: 0,
+        }
+    }
+
+    /// Set the time from the host. `now_us` is the time since boot. Returns
+    /// false, leaving the clock alone, if the time is out of range.
+    pub fn set(&mut self, unix_secs: i64, utc_offset_secs: i32, now_us: u64) -> bool {
+        let unix_at_boot_us = unix_secs
+            .checked_mul(1_000_000)
+            .and_then(|unix_us| unix_us.checked_sub(now_us as i64));
+        match unix_at_boot_us {
+            Some(boot_us) => {
+                self.unix_at_boot_us = Some(boot_us);
+                self.utc_offset_secs = utc_offset_secs;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.unix_at_boot_us.is_some()
+    }
+
+    pub fn unix_secs(&self, now_us: u64) -> Option<i64> {
+        self.unix_at_boot_us
+            .map(|boot_us| (boot_us + now_us as i64).div_euclid(1_000_000))
+    }
+
+    /// Local time, or `None` until the host has set it
+    pub fn local_time(&self, now_us: u64) -> Option<DateTime> {
+        self.unix_secs(now_us)
+            .map(|secs| DateTime::from_unix(secs + self.utc_offset_secs as i64))
+    }
+}
@@ -0,0 +1,204 @@
+//! Builds the LED frame from a stack of layers.
+//!
+//! From the bottom up: the ambient base effect, an optional reactive effect
+//! for keypresses, the tint for the active keyboard layer and the lock
+//! indicator LEDs. Each is blended onto what's below it with its own blend
+//! mode and opacity.
+
+use crate::keyboard::KeyboardLeds;
+use smart_leds::RGB8;
+
+pub const SLOT_COUNT: usize = 4;
+
+/// A level of the stack, bottom first
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Slot {
+    Base = 0,
+    Reactive = 1,
+    Tint = 2,
+    Indicators = 3,
+}
+
+impl Slot {
+    pub const ALL: [Slot; SLOT_COUNT] = [Slot::Base, Slot::Reactive, Slot::Tint, Slot::Indicators];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Slot::Base => "base",
+            Slot::Reactive => "reactive",
+            Slot::Tint => "tint",
+            Slot::Indicators => "indicators",
+        }
+    }
+
+    pub fn find(id: &str) -> Option<Slot> {
+        Self::ALL.iter().copied().find(|s| s.id() == id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Blend {
+    /// Replace what's below
+    Normal = 0,
+    Add = 1,
+    /// Darken what's below, white leaves it alone
+    Multiply = 2,
+    /// Lighten what's below, black leaves it alone
+    Screen = 3,
+    /// The brighter of the two, per channel
+    Lighten = 4,
+}
+
+impl Blend {
+    const ALL: [Blend; 5] = [
+        Blend::Normal,
+        Blend::Add,
+        Blend::Multiply,
+        Blend::Screen,
+        Blend::Lighten,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Blend::Normal => "normal",
+            Blend::Add => "add",
+            Blend::Multiply => "multiply",
+            Blend::Screen => "screen",
+            Blend::Lighten => "lighten",
+        }
+    }
+
+    pub fn find(id: &str) -> Option<Blend> {
+        Self::ALL.iter().copied().find(|b| b.id() == id)
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_u8(value: u8) -> Option<Blend> {
+        Self::ALL.get(value as usize).copied()
+    }
+
+    fn channel(&self, below: u8, above: u8) -> u8 {
+        let (b, a) = (below as u16, above as u16);
+        let ret = match self {
+            Blend::Normal => a,
+            Blend::Add => (b + a).min(255),
+            Blend::Multiply => b * a / 255,
+            Blend::Screen => 255 - (255 - b) * (255 - a) / 255,
+            Blend::Lighten => b.max(a),
+        };
+        ret as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Style {
+    pub blend: Blend,
+    /// How much of the blended result is used, out of 255
+    pub opacity: u8,
+}
+
+impl Style {
+    fn apply(&self, below: RGB8, above: RGB8) -> RGB8 {
+        let channel = |b: u8, a: u8| {
+            let blended = self.blend.channel(b, a) as u16;
+            let opacity = self.opacity as u16;
+            ((b as u16 * (255 - opacity) + blended * opacity) / 255) as u8
+        };
+        RGB8 {
+            r: channel(below.r, above.r),
+            g: channel(below.g, above.g),
+            b: channel(below.b, above.b),
+        }
+    }
+}
+
+pub type Styles = [Style; SLOT_COUNT];
+
+pub fn default_styles() -> Styles {
+    [
+        Style { blend: Blend::Normal, opacity: 255 },
+        Style { blend: Blend::Add, opacity: 255 },
+        Style { blend: Blend::Normal, opacity: 128 },
+        Style { blend: Blend::Normal, opacity: 255 },
+    ]
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Lock {
+    Caps,
+    Num,
+    Scroll,
+}
+
+/// An LED that shows a lock state, overriding the effects while it's on
+#[derive(Debug, Clone, Copy)]
+pub struct Indicator {
+    pub lock: Lock,
+    pub led: usize,
+    pub color: RGB8,
+}
+
+impl Indicator {
+    fn is_on(&self, locks: KeyboardLeds) -> bool {
+        match self.lock {
+            Lock::Caps => locks.caps_lock(),
+            Lock::Num => locks.num_lock(),
+            Lock::Scroll => locks.scroll_lock(),
+        }
+    }
+}
+
+pub struct Compositor {
+    styles: Styles,
+    indicators: &'static [Indicator],
+}
+
+impl Compositor {
+    pub fn new(indicators: &'static [Indicator]) -> Self {
+        Self {
+            styles: default_styles(),
+            indicators,
+        }
+    }
+
+    pub fn style(&self, slot: Slot) -> Style {
+        self.styles[slot as usize]
+    }
+
+    pub fn styles(&self) -> Styles {
+        self.styles
+    }
+
+    pub fn set_styles(&mut self, styles: Styles) {
+        self.styles = styles;
+    }
+
+    /// Blend a whole layer onto `frame`
+    pub fn blend(&self, slot: Slot, frame: &mut [RGB8], layer: &[RGB8]) {
+        let style = self.style(slot);
+        for (below, above) in frame.iter_mut().zip(layer.iter()) {
+            *below = style.apply(*below, *above);
+        }
+    }
+
+    /// Blend one color over every LED
+    pub fn blend_color(&self, slot: Slot, frame: &mut [RGB8], color: RGB8) {
+        let style = self.style(slot);
+        for below in frame.iter_mut() {
+            *below = style.apply(*below, color);
+        }
+    }
+
+    /// Blend the indicators that are on onto their LEDs
+    pub fn blend_indicators(&self, frame: &mut [RGB8], locks: KeyboardLeds) {
+        let style = self.style(Slot::Indicators);
+        for indicator in self.indicators.iter().filter(|i| i.is_on(locks)) {
+            if let Some(below) = frame.get_mut(indicator.led) {
+                *below = style.apply(*below, indicator.color);
+            }
+        }
+    }
+}
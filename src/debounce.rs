@@ -0,0 +1,206 @@
+//! Per-key debouncing.
+//!
+//! keyberon's `Debouncer` waits for the whole matrix to be stable before
+//! reporting anything, so every press is delayed by the full threshold. This
+//! tracks each key separately and supports a few algorithms, while still
+//! yielding the same `keyberon::layout::Event`s so `Layout` is unchanged.
+
+use keyberon::layout::Event;
+use keyberon::matrix::PressedKeys;
+
+pub const MIN_THRESHOLD: u8 = 1;
+pub const MAX_THRESHOLD: u8 = 30;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DebounceAlgorithm {
+    /// A change (press or release) is only reported once the key has read the
+    /// new state for `threshold` consecutive scans.
+    SymmetricDefer,
+    /// Presses are reported on the first scan they are seen, releases are
+    /// deferred as with `SymmetricDefer`.
+    EagerPress,
+    /// Any change is reported immediately, then the key ignores further
+    /// changes until `threshold` scans have passed.
+    PerKeyTimer,
+}
+
+impl DebounceAlgorithm {
+    pub fn next(self) -> Self {
+        match self {
+            DebounceAlgorithm::SymmetricDefer => DebounceAlgorithm::EagerPress,
+            DebounceAlgorithm::EagerPress => DebounceAlgorithm::PerKeyTimer,
+            DebounceAlgorithm::PerKeyTimer => DebounceAlgorithm::SymmetricDefer,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DebounceAlgorithm::SymmetricDefer => "defer",
+            DebounceAlgorithm::EagerPress => "eager",
+            DebounceAlgorithm::PerKeyTimer => "timer",
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            DebounceAlgorithm::SymmetricDefer => 0,
+            DebounceAlgorithm::EagerPress => 1,
+            DebounceAlgorithm::PerKeyTimer => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(DebounceAlgorithm::SymmetricDefer),
+            1 => Some(DebounceAlgorithm::EagerPress),
+            2 => Some(DebounceAlgorithm::PerKeyTimer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DebounceConfig {
+    pub algorithm: DebounceAlgorithm,
+    /// Threshold in scans (1 scan = 1ms)
+    pub threshold: u8,
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: DebounceAlgorithm::EagerPress,
+            threshold: 5,
+        }
+    }
+}
+
+impl DebounceConfig {
+    pub fn threshold_up(&mut self) {
+        self.threshold = (self.threshold + 1).min(MAX_THRESHOLD);
+    }
+
+    pub fn threshold_down(&mut self) {
+        self.threshold = self.threshold.saturating_sub(1).max(MIN_THRESHOLD);
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct KeyState {
+    pressed: bool,
+    // Scans the raw state has differed from `pressed` (defer), or scans left
+    // before the key may change again (per-key timer).
+    counter: u8,
+}
+
+impl KeyState {
+    /// Feed one raw reading, returns true if the debounced state changed.
+    fn update(&mut self, raw: bool, config: &DebounceConfig) -> bool {
+        match config.algorithm {
+            DebounceAlgorithm::SymmetricDefer => self.update_defer(raw, config.threshold),
+            DebounceAlgorithm::EagerPress => {
+                if raw && !self.pressed {
+                    self.pressed = true;
+                    self.counter = 0;
+                    true
+                } else {
+                    self.update_defer(raw, config.threshold)
+                }
+            }
+            DebounceAlgorithm::PerKeyTimer => {
+                if self.counter > 0 {
+                    self.counter -= 1;
+                    false
+                } else if raw != self.pressed {
+                    self.pressed = raw;
+                    self.counter = config.threshold;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn update_defer(&mut self, raw: bool, threshold: u8) -> bool {
+        if raw == self.pressed {
+            self.counter = 0;
+            return false;
+        }
+
+        self.counter += 1;
+        if self.counter >= threshold {
+            self.pressed = raw;
+            self.counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct KeyDebouncer<const CS: usize, const RS: usize> {
+    keys: [[KeyState; CS]; RS],
+    config: DebounceConfig,
+}
+
+impl<const CS: usize, const RS: usize> KeyDebouncer<CS, RS> {
+    pub fn new(config: DebounceConfig) -> Self {
+        Self {
+            keys: [[KeyState::default(); CS]; RS],
+            config,
+        }
+    }
+
+    pub fn config(&self) -> DebounceConfig {
+        self.config
+    }
+
+    /// Change the algorithm or threshold. Keys keep their debounced state, so
+    /// this can be done while keys are held.
+    pub fn set_config(&mut self, config: DebounceConfig) {
+        self.config = config;
+        for key in self.keys.iter_mut().flatten() {
+            key.counter = 0;
+        }
+    }
+
+    /// Feed a new matrix reading, returning the resulting press and release
+    /// events. As with keyberon's `Debouncer::events`, the returned iterator
+    /// must be consumed for the state to be fully updated.
+    pub fn events(&mut self, new: PressedKeys<CS, RS>) -> DebounceEvents<'_, CS, RS> {
+        DebounceEvents {
+            debouncer: self,
+            new,
+            index: 0,
+        }
+    }
+}
+
+pub struct DebounceEvents<'a, const CS: usize, const RS: usize> {
+    debouncer: &'a mut KeyDebouncer<CS, RS>,
+    new: PressedKeys<CS, RS>,
+    index: usize,
+}
+
+impl<'a, const CS: usize, const RS: usize> Iterator for DebounceEvents<'a, CS, RS> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        while self.index < CS * RS {
+            let (i, j) = (self.index / CS, self.index % CS);
+            self.index += 1;
+
+            let config = self.debouncer.config;
+            let key = &mut self.debouncer.keys[i][j];
+            if key.update(self.new.0[i][j], &config) {
+                return Some(if key.pressed {
+                    Event::Press(i as u8, j as u8)
+                } else {
+                    Event::Release(i as u8, j as u8)
+                });
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,156 @@
+//! Switch bounce and chatter diagnostics.
+//!
+//! Watches the raw matrix output before debouncing. Every raw transition
+//! that follows the previous transition of the same key within
+//! `BOUNCE_WINDOW_TICKS` counts as a bounce. A re-press after a release that
+//! outlasted the bounce window but was still shorter than
+//! `CHATTER_WINDOW_TICKS` counts as chatter: nobody types that fast, so it
+//! points at a failing switch rather than the firmware.
+
+use core::fmt;
+use keyberon::matrix::PressedKeys;
+
+const BOUNCE_WINDOW_TICKS: u32 = 5;
+const CHATTER_WINDOW_TICKS: u32 = 30;
+
+#[derive(Clone, Copy, Default)]
+pub struct KeyStats {
+    pub bounces: u16,
+    pub chatters: u16,
+    /// Shortest interval between two raw transitions, in ticks
+    pub min_interval: Option<u16>,
+    last_change: Option<u32>,
+    last_release: Option<u32>,
+}
+
+impl KeyStats {
+    fn score(&self) -> u32 {
+        // Chatter makes it through debouncing, so weigh it well above bounces
+        self.chatters as u32 * 100 + self.bounces as u32
+    }
+}
+
+/// A chatter event, returned so that it can be reported as it happens
+#[derive(Clone, Copy)]
+pub struct Chatter {
+    pub row: usize,
+    pub col: usize,
+    pub gap: u32,
+    pub count: u16,
+}
+
+#[derive(Clone, Copy)]
+pub struct Offender {
+    pub row: usize,
+    pub col: usize,
+    pub stats: KeyStats,
+}
+
+impl fmt::Display for Offender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "R{}C{} c{} b{}",
+            self.row, self.col, self.stats.chatters, self.stats.bounces
+        )?;
+        if let Some(min_interval) = self.stats.min_interval {
+            write!(f, " {}ms", min_interval)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Diagnostics<const CS: usize, const RS: usize> {
+    stats: [[KeyStats; CS]; RS],
+    last_raw: PressedKeys<CS, RS>,
+    tick_count: u32,
+}
+
+impl<const CS: usize, const RS: usize> Diagnostics<CS, RS> {
+    pub fn new() -> Self {
+        Self {
+            stats: [[KeyStats::default(); CS]; RS],
+            last_raw: PressedKeys::default(),
+            tick_count: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Feed one raw matrix scan. Returns the first chatter seen in this scan,
+    /// if any.
+    pub fn update(&mut self, raw: &PressedKeys<CS, RS>) -> Option<Chatter> {
+        self.tick_count = self.tick_count.wrapping_add(1);
+        let now = self.tick_count;
+        let mut chatter = None;
+
+        for (i, row) in raw.0.iter().enumerate() {
+            for (j, &pressed) in row.iter().enumerate() {
+                if pressed == self.last_raw.0[i][j] {
+                    continue;
+                }
+
+                let stats = &mut self.stats[i][j];
+                if let Some(last_change) = stats.last_change {
+                    let interval = now.wrapping_sub(last_change);
+                    if interval <= BOUNCE_WINDOW_TICKS {
+                        stats.bounces = stats.bounces.saturating_add(1);
+                    }
+                    let interval = interval.min(u16::MAX as u32) as u16;
+                    stats.min_interval = Some(stats.min_interval.map_or(interval, |m| m.min(interval)));
+                }
+
+                if pressed {
+                    if let Some(last_release) = stats.last_release {
+                        let gap = now.wrapping_sub(last_release);
+                        if gap > BOUNCE_WINDOW_TICKS && gap < CHATTER_WINDOW_TICKS {
+                            stats.chatters = stats.chatters.saturating_add(1);
+                            chatter.get_or_insert(Chatter {
+                                row: i,
+                                col: j,
+                                gap,
+                                count: stats.chatters,
+                            });
+                        }
+                    }
+                } else {
+                    stats.last_release = Some(now);
+                }
+                stats.last_change = Some(now);
+            }
+        }
+
+        self.last_raw = raw.clone();
+        return chatter;
+    }
+
+    /// The keys with the most chatter (then bounces), worst first. Keys with
+    /// nothing recorded are left out.
+    pub fn worst_offenders<const N: usize>(&self) -> heapless::Vec<Offender, N> {
+        let mut worst: heapless::Vec<Offender, N> = heapless::Vec::new();
+
+        for (i, row) in self.stats.iter().enumerate() {
+            for (j, stats) in row.iter().enumerate() {
+                if stats.score() == 0 {
+                    continue;
+                }
+
+                let offender = Offender { row: i, col: j, stats: *stats };
+                if !worst.is_full() {
+                    let _ = worst.push(offender);
+                } else if let Some(last) = worst.last_mut() {
+                    if last.stats.score() < stats.score() {
+                        *last = offender;
+                    } else {
+                        continue;
+                    }
+                }
+                worst.sort_unstable_by(|a, b| b.stats.score().cmp(&a.stats.score()));
+            }
+        }
+
+        return worst;
+    }
+}
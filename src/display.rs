@@ -1,87 +1,365 @@
+use crate::animation::Animation;
+use crate::blanking::{Blanking, BlankingConfig, BlankingState};
+use crate::clock::DateTime;
+use crate::debounce::DebounceConfig;
+use crate::effects::{Param, Params};
+use crate::framebuffer::FrameBuffer;
+use crate::keyboard::{KeyboardLeds, MediaKey};
+use crate::notify::{Notification, URGENT_PRIORITY};
+use crate::pages::{self, Pages};
+use crate::screen::{PageContext, PageId, PageUpdate, Screen};
+use crate::wpm::WpmTracker;
 use embedded_graphics::{
-    image::Image,
     mono_font::{ascii::FONT_9X18_BOLD, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
     prelude::*,
     text::{Baseline, Text},
 };
-use ssd1306::{mode::BufferedGraphicsMode, prelude::*, Ssd1306};
-use tinybmp::Bmp;
-
-const BONGO_IDLE: &[u8] = include_bytes!("../images/bongo_1.bmp");
-const BONGO_TAP_1: &[u8] = include_bytes!("../images/bongo_2.bmp");
-const BONGO_TAP_2: &[u8] = include_bytes!("../images/bongo_3.bmp");
-const BONGO_TAP: [&[u8]; 2] = [include_bytes!("../images/bongo_2.bmp"),  include_bytes!("../images/bongo_3.bmp")];
-
-pub struct CaeDisplay<I> {
-    display: Ssd1306<I2CInterface<I>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>,
-    bongo_cnt: usize,
-    last_keypress: u32,
-    tick_cnt: u32
+use ssd1306::{command::AddrMode, mode::BasicMode, prelude::*, Ssd1306};
+
+const WPM_UPDATE_US: u64 = 250_000;
+const WPM_HISTORY: usize = 128;
+// How long temporary pages stay up before going back to the home page
+const TEXT_PAGE_US: u64 = 10_000_000;
+const LOCKS_PAGE_US: u64 = 2_000_000;
+const NOTIFY_PAGE_US: u64 = 5_000_000;
+const MEDIA_PAGE_US: u64 = 1_500_000;
+const PARAMS_PAGE_US: u64 = 1_500_000;
+// Bytes pushed to the panel per tick. At 400kHz each byte takes ~23us on the
+// bus. Setting the draw area costs about 10 bytes and the data write 2 more
+// on top of the chunk, so a tick's transfer stays around 0.45ms of the 1ms
+// scan. A full screen takes 128 ticks.
+const CHUNK_LEN: usize = 8;
+
+/// Panel setup. The panel size is a type parameter of `CaeDisplay`, as it is
+/// for `Ssd1306`.
+#[derive(Clone, Copy)]
+pub struct OledConfig {
+    pub rotation: DisplayRotation,
+    pub address: u8,
+    /// Burn-in protection timeouts. Fixed at build time, set them where
+    /// `OledConfig` is built in `main`.
+    pub blanking: BlankingConfig,
+}
+
+impl Default for OledConfig {
+    fn default() -> Self {
+        Self {
+            rotation: DisplayRotation::Rotate0,
+            address: 0x3C,
+            blanking: BlankingConfig::default(),
+        }
+    }
+}
+
+pub struct CaeDisplay<I, SIZE: DisplaySize = DisplaySize128x64> {
+    display: Ssd1306<I2CInterface<I>, SIZE, BasicMode>,
+    frame: FrameBuffer,
+    screen: Screen,
+    pages: Pages,
+    ctx: PageContext,
+    wpm: WpmTracker<WPM_HISTORY>,
+    last_wpm_update_us: u64,
+    blanking: Blanking,
 }
 
-impl<I> CaeDisplay<I>
+impl<I, SIZE> CaeDisplay<I, SIZE>
 where
     I: embedded_hal::blocking::i2c::Write,
+    SIZE: DisplaySize,
 {
-    /// Create new builder with a default I2C address of 0x3C
-    pub fn new(i2c: I) -> Self
+    pub fn new(i2c: I, size: SIZE, config: OledConfig) -> Self
     where
         I: embedded_hal::blocking::i2c::Write,
     {
         // Create the I²C display interface:
-        let interface = ssd1306::I2CDisplayInterface::new(i2c);
+        let interface = ssd1306::I2CDisplayInterface::new_custom_address(i2c, config.address);
 
         // Create a driver instance and initialize:
-        let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode();
-        display.init().unwrap();
+        let mut display = Ssd1306::new(interface, size, config.rotation);
+        display.init_with_addr_mode(AddrMode::Horizontal).unwrap();
+
+        let transposed = match config.rotation {
+            DisplayRotation::Rotate0 | DisplayRotation::Rotate180 => false,
+            DisplayRotation::Rotate90 | DisplayRotation::Rotate270 => true,
+        };
 
         let mut display = Self { 
             display,
-            bongo_cnt: 0,
-            last_keypress: 0,
-            tick_cnt: 0
+            frame: FrameBuffer::new(SIZE::WIDTH as usize, SIZE::HEIGHT as usize, transposed),
+            screen: Screen::new(PageId::Bongo),
+            pages: Pages::new(),
+            ctx: PageContext {
+                now_us: 0,
+                wpm: 0,
+                layer: 0,
+                layer_name: "",
+                locks: KeyboardLeds::default(),
+                led_effect_name: "",
+                debounce: DebounceConfig::default(),
+                usb_configured: false,
+                time: None,
+            },
+            wpm: WpmTracker::new(),
+            last_wpm_update_us: 0,
+            blanking: Blanking::new(config.blanking),
         };
         
-        display.draw_image(BONGO_IDLE);
+        // Start on the splash, it goes to the home page by itself
+        display.screen.show(PageId::Splash);
+        display.enter_page();
+        display.flush_all();
 
         return display;
     }
 
-    fn draw_image(&mut self, bytes: &[u8]) {
-        self.display.clear();
-        let bmp = Bmp::<BinaryColor>::from_slice(bytes).unwrap();
-        Image::new(&bmp, Point::new(0, 0))
-            .draw(&mut self.display)
-            .unwrap();
-        self.display.flush().unwrap();
+    fn render(&mut self) {
+        if self.blanking.state() == BlankingState::Off {
+            return;
+        }
+
+        self.frame.clear();
+        let current = self.screen.current();
+        let mut target = self.frame.translated(self.blanking.offset());
+        self.pages.render(current, &self.ctx, &mut target).unwrap();
+        // Layers other than the base get a badge on every other page
+        if self.ctx.layer != 0 && current != PageId::Layer {
+            pages::draw_layer_badge(&self.ctx, &mut target).unwrap();
+        }
     }
 
-    pub fn handle_keypress(&mut self) {
-        match self.bongo_cnt {
-            0 => self.draw_image(BONGO_TAP_1),
-            _ => self.draw_image(BONGO_TAP_2)
-        };
+    /// Send one chunk of changes to the panel
+    fn flush_chunk(&mut self) {
+        if let Some(chunk) = self.frame.take_chunk(CHUNK_LEN) {
+            let column = chunk.column + SIZE::OFFSETX;
+            let row = chunk.page * 8 + SIZE::OFFSETY;
+            let len = chunk.data().len() as u8;
+            self.display
+                .set_draw_area((column, row), (column + len, row + 8))
+                .unwrap();
+            self.display.draw(chunk.data()).unwrap();
+        }
+    }
+
+    /// Send all outstanding changes, blocking. Only for use outside the scan
+    /// loop.
+    fn flush_all(&mut self) {
+        while self.frame.is_dirty() {
+            self.flush_chunk();
+        }
+    }
 
-        self.last_keypress = self.tick_cnt;
+    /// Bring the display back from any burn-in protection
+    fn restore(&mut self) {
+        self.display.set_brightness(Brightness::NORMAL).unwrap();
+        self.display.set_display_on(true).unwrap();
+        self.render();
+    }
+
+    fn update_blanking(&mut self, now_us: u64) {
+        let before = self.blanking.state();
+        if !self.blanking.tick(now_us) {
+            return;
+        }
 
-        // There must be some better way to do this with iterators, however I couldnt
-        // find a way to store an iterator in the struct; the typing looks overcomplicated
-        // for something that should be simple..
-        self.bongo_cnt += 1;
-        if self.bongo_cnt >= BONGO_TAP.len() {
-            self.bongo_cnt = 0;
+        match self.blanking.state() {
+            BlankingState::Off => self.display.set_display_on(false).unwrap(),
+            BlankingState::Dimmed if before != BlankingState::Dimmed => {
+                self.display.set_brightness(Brightness::DIMMEST).unwrap();
+                self.render();
+            }
+            _ => self.render(),
         }
     }
 
-    // TODO: Fix this crap to use a proper SM and real timing
-    pub fn tick(&mut self) {
-        self.tick_cnt += 1;
-        
-        if (self.last_keypress + 400) == self.tick_cnt {
-            self.draw_image(BONGO_IDLE);
+    fn enter_page(&mut self) {
+        self.pages.enter(self.screen.current(), &self.ctx);
+        self.render();
+    }
+
+    fn apply(&mut self, update: PageUpdate) {
+        match update {
+            PageUpdate::Idle => (),
+            PageUpdate::Redraw => self.render(),
+            PageUpdate::Exit => {
+                self.screen.go_home();
+                self.enter_page();
+            }
+        }
+    }
+
+    pub fn handle_keypress(&mut self, now_us: u64) {
+        self.ctx.now_us = now_us;
+        self.wpm.handle_keypress(now_us);
+        // Don't wait for the next periodic update, or the first press after
+        // a pause still sees 0 WPM and the bongo cat stops tapping again
+        self.ctx.wpm = self.wpm.wpm(now_us);
+        self.last_wpm_update_us = now_us;
+        if self.blanking.activity(now_us) {
+            self.restore();
+        }
+
+        let update = self.pages.handle_keypress(self.screen.current(), &self.ctx);
+        self.apply(update);
+    }
+
+    pub fn tick(&mut self, now_us: u64) {
+        self.ctx.now_us = now_us;
+
+        if now_us - self.last_wpm_update_us >= WPM_UPDATE_US {
+            self.last_wpm_update_us = now_us;
+            self.ctx.wpm = self.wpm.wpm(now_us);
+        }
+
+        self.update_blanking(now_us);
+
+        // The notifications page expires them itself, so it can redraw
+        if self.screen.current() != PageId::Notify {
+            self.pages.notify.notifications.expire(now_us);
+        }
+
+        if self.screen.check_expiry(now_us) {
+            self.enter_page();
+        } else {
+            let update = self.pages.update(self.screen.current(), &self.ctx);
+            self.apply(update);
+        }
+
+        self.flush_chunk();
+    }
+
+    /// Cycle the home page
+    pub fn next_page(&mut self) {
+        self.screen.next_home();
+        self.enter_page();
+    }
+
+    /// Show a few lines of small text for a while, or until the next keypress
+    pub fn show_text(&mut self, lines: &[&str]) {
+        self.pages.text.set_lines(lines);
+        self.screen.show_for(PageId::Text, self.ctx.now_us, TEXT_PAGE_US);
+        self.enter_page();
+    }
+
+    /// Play one of the `images` animations once through, or until the next
+    /// keypress
+    pub fn play_animation(&mut self, animation: &'static Animation) {
+        self.pages.animation.set_animation(animation);
+        self.screen.show_for(PageId::Animation, self.ctx.now_us, animation.duration_us());
+        self.enter_page();
+    }
+
+    /// Pop up the overlay for a media key
+    pub fn show_media_key(&mut self, key: MediaKey) {
+        self.pages.media.press(key);
+        if self.screen.current() != PageId::Splash {
+            self.screen.show_for(PageId::Media, self.ctx.now_us, MEDIA_PAGE_US);
+            self.enter_page();
+        }
+    }
+
+    /// Pop up the value of an LED effect parameter that's being adjusted
+    pub fn show_params(&mut self, effect: &'static str, params: Option<Params>, param: Param) {
+        self.pages.params.set(effect, params, param);
+        if self.screen.current() != PageId::Splash {
+            self.screen.show_for(PageId::Params, self.ctx.now_us, PARAMS_PAGE_US);
+            self.enter_page();
+        }
+    }
+
+    /// Volume level reported by the host, replacing the estimate
+    pub fn set_volume(&mut self, percent: u8) {
+        self.pages.media.set_volume(percent);
+        if self.screen.current() == PageId::Media {
+            self.render();
+        }
+    }
+
+    /// Add or update a host notification. If it ends up on top it pops up for
+    /// a while, or until a keypress if it is urgent. Priority 0 notifications
+    /// only show on the notifications page.
+    pub fn notify(&mut self, notification: Notification) {
+        let (id, priority) = (notification.id, notification.priority);
+        let notifications = &mut self.pages.notify.notifications;
+        // Expired ones mustn't keep a new one off the top
+        notifications.expire(self.ctx.now_us);
+        notifications.add(notification, self.ctx.now_us);
+        let on_top = notifications.top().map_or(false, |n| n.id == id);
+
+        let current = self.screen.current();
+        if current == PageId::Notify {
+            self.render();
+        } else if priority > 0 && on_top && current != PageId::Splash {
+            if self.blanking.activity(self.ctx.now_us) {
+                self.restore();
+            }
+            if priority >= URGENT_PRIORITY {
+                self.screen.show(PageId::Notify);
+            } else {
+                self.screen.show_for(PageId::Notify, self.ctx.now_us, NOTIFY_PAGE_US);
+            }
+            self.enter_page();
+        }
+    }
+
+    /// Remove the notification with `id`, or all of them
+    pub fn clear_notification(&mut self, id: Option<u8>) {
+        let notifications = &mut self.pages.notify.notifications;
+        match id {
+            Some(id) => notifications.remove(id),
+            None => notifications.clear(),
+        }
+
+        if self.screen.current() == PageId::Notify {
+            if notifications.top().is_none() {
+                self.apply(PageUpdate::Exit);
+            } else {
+                self.render();
+            }
+        }
+    }
+
+    pub fn set_layer(&mut self, layer: usize, name: &'static str) {
+        if layer == self.ctx.layer {
+            return;
+        }
+        self.ctx.layer = layer;
+        self.ctx.layer_name = name;
+        self.render();
+    }
+
+    /// Update the lock state. Changes pop up the locks page for a moment,
+    /// unless that is already the page being shown.
+    pub fn set_locks(&mut self, locks: KeyboardLeds) {
+        if locks == self.ctx.locks {
+            return;
         }
+
+        self.ctx.locks = locks;
+        let current = self.screen.current();
+        if current != PageId::Locks && current != PageId::Splash {
+            self.screen.show_for(PageId::Locks, self.ctx.now_us, LOCKS_PAGE_US);
+            self.enter_page();
+        }
+    }
+
+    pub fn set_usb_configured(&mut self, configured: bool) {
+        self.ctx.usb_configured = configured;
+    }
+
+    pub fn set_time(&mut self, time: Option<DateTime>) {
+        self.ctx.time = time;
+    }
+
+    pub fn set_status(&mut self, led_effect_name: &'static str, debounce: DebounceConfig) {
+        self.ctx.led_effect_name = led_effect_name;
+        self.ctx.debounce = debounce;
+    }
+
+    /// Turn the panel on or off. The buffer is kept, so turning it back on
+    /// shows whatever was last drawn.
+    pub fn set_power(&mut self, on: bool) {
+        self.display.set_display_on(on).unwrap();
     }
 
     pub fn _test_draw_text(&mut self) {
@@ -92,12 +370,12 @@ where
             .build();
 
         // Empty the display:
-        self.display.clear();
+        self.frame.clear();
         // Draw 3 lines of text:
         Text::with_baseline("Joelteon!", Point::zero(), text_style, Baseline::Top)
-            .draw(&mut self.display)
+            .draw(&mut self.frame)
             .unwrap();
 
-        self.display.flush().unwrap();
+        self.flush_all();
     }
 }
@@ -0,0 +1,1070 @@
+//! LED effects.
+//!
+//! Each effect implements `Effect` and keeps its own state. `Effects` is the
+//! registry of all of them, looked up by index; the index is what gets stored
+//! in settings and what the mode keys select, so only append to it.
+//!
+//! Effects are timed from the `now_us` passed to `tick`, so their speed
+//! doesn't depend on the scan rate. Most take a hue, saturation, value and
+//! speed that can be changed at runtime, see `Params`. The registry holds
+//! these and passes them in, so they can be saved with the other settings.
+
+use crate::led_map::{LedMap, Position, KEY_UNIT};
+use rand_core::RngCore;
+use smart_leds::RGB8;
+
+pub const RAINBOW: usize = 0;
+pub const LIGHTNING: usize = 1;
+pub const CHASE: usize = 2;
+pub const CHASE_2: usize = 3;
+pub const HEATMAP: usize = 4;
+pub const RIPPLE: usize = 5;
+pub const SPLASH: usize = 6;
+pub const GLOW: usize = 7;
+pub const SOLID: usize = 8;
+pub const BREATHING: usize = 9;
+pub const GRADIENT: usize = 10;
+pub const FIRE: usize = 11;
+pub const STARLIGHT: usize = 12;
+/// Number of effects in the registry
+pub const COUNT: usize = 13;
+
+// Most recent presses tracked by the ripple and splash effects
+const MAX_PRESSES: usize = 8;
+const RIPPLE_US: u64 = 1_000_000;
+// Ring speed at speed 0, in position units per second, and width
+const RIPPLE_MIN_SPEED: u64 = 2 * KEY_UNIT as u64;
+const RIPPLE_WIDTH: u16 = KEY_UNIT as u16;
+const SPLASH_RADIUS: u16 = 2 * KEY_UNIT as u16;
+/// Change per press of the parameter keys
+pub const PARAM_STEP: u8 = 16;
+// Breathing period at the fastest speed, plus this much per step slower
+const BREATHE_MIN_US: u64 = 1_000_000;
+const BREATHE_STEP_US: u64 = 30_000;
+// How much of the color wheel the gradient covers
+const GRADIENT_SPAN: usize = 128;
+const FIRE_COOLING: u32 = 16;
+const STAR_FADE: u8 = 3;
+const STAR_CHANCE: u32 = 8;
+
+const OFF: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+pub trait Effect<const N: usize> {
+    /// Short lowercase id, e.g. for the host channel
+    fn id(&self) -> &'static str;
+
+    /// Name shown on the OLED
+    fn name(&self) -> &'static str;
+
+    /// Called when the effect is selected
+    fn init(&mut self);
+
+    /// Called every scan tick (1ms), `now_us` is from `PicoClock`
+    fn tick(&mut self, now_us: u64, params: &Params, rng: &mut dyn RngCore);
+
+    /// `key` is where the pressed key is, if it came from the matrix
+    fn handle_keypress(
+        &mut self,
+        _key: Option<Position>,
+        _map: &LedMap<N>,
+        _params: &Params,
+        _rng: &mut dyn RngCore,
+    ) {
+    }
+
+    /// Write the current frame into `leds`
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]);
+}
+
+/// Runtime adjustable effect parameters
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Params {
+    /// 0 red, 85 green, 170 blue, see `hsv_rgb`. Effects that cycle through
+    /// colors start from here.
+    pub hue: u8,
+    pub sat: u8,
+    pub val: u8,
+    /// 0 is slowest, or still for effects that can be, up to 255
+    pub speed: u8,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Param {
+    Hue,
+    Saturation,
+    Value,
+    Speed,
+}
+
+impl Param {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Param::Hue => "Hue",
+            Param::Saturation => "Saturation",
+            Param::Value => "Value",
+            Param::Speed => "Speed",
+        }
+    }
+}
+
+impl Params {
+    /// Fully saturated and at full value
+    const fn new(hue: u8, speed: u8) -> Self {
+        Self {
+            hue,
+            sat: 255,
+            val: 255,
+            speed,
+        }
+    }
+
+    pub fn get(&self, param: Param) -> u8 {
+        match param {
+            Param::Hue => self.hue,
+            Param::Saturation => self.sat,
+            Param::Value => self.val,
+            Param::Speed => self.speed,
+        }
+    }
+
+    /// Step `param` by `PARAM_STEP`. Hue wraps round, the others stop at the
+    /// ends.
+    pub fn step(&mut self, param: Param, up: bool) {
+        let step = |v: u8| {
+            if up {
+                v.saturating_add(PARAM_STEP)
+            } else {
+                v.saturating_sub(PARAM_STEP)
+            }
+        };
+        match param {
+            Param::Hue if up => self.hue = self.hue.wrapping_add(PARAM_STEP),
+            Param::Hue => self.hue = self.hue.wrapping_sub(PARAM_STEP),
+            Param::Saturation => self.sat = step(self.sat),
+            Param::Value => self.val = step(self.val),
+            Param::Speed => self.speed = step(self.speed),
+        }
+    }
+
+    fn color(&self) -> RGB8 {
+        hsv_rgb(self.hue, self.sat, self.val)
+    }
+
+    /// The color `offset` further round the hue circle
+    fn color_at(&self, offset: u8) -> RGB8 {
+        hsv_rgb(self.hue.wrapping_add(offset), self.sat, self.val)
+    }
+
+    /// A period of `slowest_us` at speed 0, shrinking to `fastest_us` at 255
+    fn period_us(&self, slowest_us: u64, fastest_us: u64) -> u64 {
+        slowest_us - (slowest_us - fastest_us) * self.speed as u64 / 255
+    }
+}
+
+/// Hue, saturation and value, each 0-255, to RGB
+pub fn hsv_rgb(hue: u8, sat: u8, val: u8) -> RGB8 {
+    // Six sectors of 43 hues each, and how far through its sector `hue` is
+    let sector = hue / 43;
+    let rem = (hue - sector * 43) as u32 * 6;
+
+    let (s, v) = (sat as u32, val as u32);
+    let p = (v * (255 - s) / 255) as u8;
+    let q = (v * (255 - s * rem / 255) / 255) as u8;
+    let t = (v * (255 - s * (255 - rem) / 255) / 255) as u8;
+
+    let (r, g, b) = match sector {
+        0 => (val, t, p),
+        1 => (q, val, p),
+        2 => (p, val, t),
+        3 => (p, q, val),
+        4 => (t, p, val),
+        _ => (val, p, q),
+    };
+    return RGB8 { r, g, b };
+}
+
+// Blue for rarely used through green to red for the most used. Unused is
+// left off.
+fn heat_rgb(level: u8) -> RGB8 {
+    if level == 0 {
+        RGB8 { r: 0, g: 0, b: 0 }
+    } else if level < 128 {
+        RGB8 { r: 0, g: level * 2, b: 255 - level * 2 }
+    } else {
+        let level = level - 128;
+        RGB8 { r: level * 2, g: 255 - level * 2, b: 0 }
+    }
+}
+
+fn fade(leds: &mut [RGB8], amount: u8) {
+    for led in leds.iter_mut() {
+        led.r = led.r.saturating_sub(amount);
+        led.g = led.g.saturating_sub(amount);
+        led.b = led.b.saturating_sub(amount);
+    }
+}
+
+/// `color` dimmed to `level` out of 255
+fn scale(color: RGB8, level: u8) -> RGB8 {
+    let channel = |c: u8| (c as u16 * level as u16 / 255) as u8;
+    RGB8 {
+        r: channel(color.r),
+        g: channel(color.g),
+        b: channel(color.b),
+    }
+}
+
+fn add(led: &mut RGB8, color: RGB8) {
+    led.r = led.r.saturating_add(color.r);
+    led.g = led.g.saturating_add(color.g);
+    led.b = led.b.saturating_add(color.b);
+}
+
+/// 255 at the start of `duration_us`, falling to 0 at the end
+fn fade_out(age_us: u64, duration_us: u64) -> u8 {
+    if age_us >= duration_us {
+        return 0;
+    }
+    (255 - age_us * 255 / duration_us) as u8
+}
+
+fn one_in_chance(rng: &mut dyn RngCore, chance: u32) -> bool {
+    rand_index(rng, chance as usize) == 0
+}
+
+fn rand_index(rng: &mut dyn RngCore, len: usize) -> usize {
+    rng.next_u32() as usize % len
+}
+
+/// Fires at most once every `period_us`, timed from `PicoClock`
+struct Interval {
+    last_us: u64,
+}
+
+impl Interval {
+    const fn new() -> Self {
+        Self { last_us: 0 }
+    }
+
+    fn elapsed(&mut self, now_us: u64, period_us: u64) -> bool {
+        if now_us.saturating_sub(self.last_us) < period_us {
+            return false;
+        }
+        self.last_us = now_us;
+        return true;
+    }
+}
+
+pub struct Rainbow<const N: usize> {
+    wheel_positions: [u8; N],
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Rainbow<N> {
+    fn id(&self) -> &'static str {
+        "rainbow"
+    }
+
+    fn name(&self) -> &'static str {
+        "Rainbow"
+    }
+
+    fn init(&mut self) {
+        let step = u8::MAX as f32 / N as f32;
+
+        for (i, wheel_pos) in self.wheel_positions.iter_mut().enumerate() {
+            *wheel_pos = (i as f32 * step) as u8;
+        }
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, _rng: &mut dyn RngCore) {
+        if !self.interval.elapsed(now_us, params.period_us(21_000, 1_000)) {
+            return;
+        }
+        for wheel_pos in self.wheel_positions.iter_mut() {
+            *wheel_pos = wheel_pos.wrapping_add(1);
+        }
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        for (led, wheel_pos) in leds.iter_mut().zip(self.wheel_positions.iter()) {
+            *led = params.color_at(*wheel_pos);
+        }
+    }
+}
+
+// Either the color, or a paler version of it
+fn flash_color(params: &Params, rng: &mut dyn RngCore) -> RGB8 {
+    if one_in_chance(rng, 2) {
+        params.color()
+    } else {
+        hsv_rgb(params.hue, params.sat / 2, params.val)
+    }
+}
+
+/// Random flashes over time, and under each key as it's pressed
+pub struct Lightning<const N: usize> {
+    leds: [RGB8; N],
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Lightning<N> {
+    fn id(&self) -> &'static str {
+        "lightning"
+    }
+
+    fn name(&self) -> &'static str {
+        "Lightning"
+    }
+
+    fn init(&mut self) {
+        self.leds = [OFF; N];
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, rng: &mut dyn RngCore) {
+        if !self.interval.elapsed(now_us, params.period_us(21_000, 1_000)) {
+            return;
+        }
+
+        if one_in_chance(rng, 20) {
+            let index = rand_index(rng, N);
+            let led = self.leds[index];
+
+            // Flashes over time are a little dimmer than keypresses
+            if led.r.max(led.g).max(led.b) < 100 {
+                self.leds[index] = scale(flash_color(params, rng), 200);
+            }
+        }
+
+        fade(&mut self.leds, 2);
+    }
+
+    fn handle_keypress(
+        &mut self,
+        key: Option<Position>,
+        map: &LedMap<N>,
+        params: &Params,
+        rng: &mut dyn RngCore,
+    ) {
+        let index = match key {
+            Some(pos) => map.nearest_leds::<1>(pos)[0],
+            None => rand_index(rng, N),
+        };
+
+        self.leds[index] = flash_color(params, rng);
+    }
+
+    fn render(&self, _params: &Params, leds: &mut [RGB8; N]) {
+        *leds = self.leds;
+    }
+}
+
+/// Each keypress lights the next LED along, in the next color
+pub struct Chase<const N: usize> {
+    leds: [RGB8; N],
+    position: usize,
+    wheel_pos: u8,
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Chase<N> {
+    fn id(&self) -> &'static str {
+        "chase"
+    }
+
+    fn name(&self) -> &'static str {
+        "Chase"
+    }
+
+    fn init(&mut self) {
+        self.leds = [OFF; N];
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, _rng: &mut dyn RngCore) {
+        if self.interval.elapsed(now_us, params.period_us(21_000, 1_000)) {
+            fade(&mut self.leds, 1);
+        }
+    }
+
+    fn handle_keypress(
+        &mut self,
+        _key: Option<Position>,
+        _map: &LedMap<N>,
+        params: &Params,
+        _rng: &mut dyn RngCore,
+    ) {
+        self.position = (self.position + 1) % N;
+        self.wheel_pos = self.wheel_pos.wrapping_add(10);
+        self.leds[self.position] = params.color_at(self.wheel_pos);
+    }
+
+    fn render(&self, _params: &Params, leds: &mut [RGB8; N]) {
+        *leds = self.leds;
+    }
+}
+
+/// Two dots chasing each other round the strip, changing color as they go
+pub struct Chase2<const N: usize> {
+    leds: [RGB8; N],
+    position: usize,
+    wheel_pos: u8,
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Chase2<N> {
+    fn id(&self) -> &'static str {
+        "chase2"
+    }
+
+    fn name(&self) -> &'static str {
+        "Chase 2"
+    }
+
+    fn init(&mut self) {
+        self.leds = [OFF; N];
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, _rng: &mut dyn RngCore) {
+        if !self.interval.elapsed(now_us, params.period_us(200_000, 2_000)) {
+            return;
+        }
+
+        self.position = (self.position + 1) % N;
+        let opposite = (self.position + N / 2) % N;
+
+        self.wheel_pos = self.wheel_pos.wrapping_add(10);
+        self.leds[self.position] = params.color_at(self.wheel_pos);
+        self.leds[opposite] = params.color_at(self.wheel_pos);
+
+        fade(&mut self.leds, 20);
+    }
+
+    fn render(&self, _params: &Params, leds: &mut [RGB8; N]) {
+        *leds = self.leds;
+    }
+}
+
+/// Per-LED key usage, from `crate::heatmap`
+pub struct HeatmapEffect<const N: usize> {
+    levels: [u8; N],
+}
+
+impl<const N: usize> HeatmapEffect<N> {
+    /// Per-LED key usage, 0-255
+    pub fn set_levels(&mut self, levels: [u8; N]) {
+        self.levels = levels;
+    }
+}
+
+impl<const N: usize> Effect<N> for HeatmapEffect<N> {
+    fn id(&self) -> &'static str {
+        "heatmap"
+    }
+
+    fn name(&self) -> &'static str {
+        "Heatmap"
+    }
+
+    fn init(&mut self) {}
+
+    fn tick(&mut self, _now_us: u64, _params: &Params, _rng: &mut dyn RngCore) {}
+
+    fn render(&self, _params: &Params, leds: &mut [RGB8; N]) {
+        for (led, level) in leds.iter_mut().zip(self.levels.iter()) {
+            *led = heat_rgb(*level);
+        }
+    }
+}
+
+/// A keypress for the ripple and splash effects to spread out from
+#[derive(Clone, Copy)]
+struct Press<const N: usize> {
+    start_us: u64,
+    /// From the key to each LED, less the distance to the nearest LED, so
+    /// keys far from the strip still light it straight away
+    distances: [u16; N],
+    color: RGB8,
+}
+
+/// The last few presses, oldest overwritten first
+struct Presses<const N: usize> {
+    presses: [Option<Press<N>>; MAX_PRESSES],
+    next: usize,
+    wheel_pos: u8,
+}
+
+impl<const N: usize> Presses<N> {
+    const fn new() -> Self {
+        Self {
+            presses: [None; MAX_PRESSES],
+            next: 0,
+            wheel_pos: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.presses = [None; MAX_PRESSES];
+    }
+
+    /// Record a press at `key`, each in the next color along from the hue
+    fn add(&mut self, key: Position, map: &LedMap<N>, now_us: u64, params: &Params) {
+        let mut distances = map.distances(key);
+        let nearest = distances.iter().copied().min().unwrap_or(0);
+        for dist in distances.iter_mut() {
+            *dist -= nearest;
+        }
+
+        self.wheel_pos = self.wheel_pos.wrapping_add(40);
+        self.presses[self.next] = Some(Press {
+            start_us: now_us,
+            distances,
+            color: params.color_at(self.wheel_pos),
+        });
+        self.next = (self.next + 1) % MAX_PRESSES;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Press<N>> {
+        self.presses.iter().flatten()
+    }
+}
+
+/// Rings expanding outward from each pressed key
+pub struct Ripple<const N: usize> {
+    presses: Presses<N>,
+    now_us: u64,
+}
+
+impl<const N: usize> Effect<N> for Ripple<N> {
+    fn id(&self) -> &'static str {
+        "ripple"
+    }
+
+    fn name(&self) -> &'static str {
+        "Ripple"
+    }
+
+    fn init(&mut self) {
+        self.presses.clear();
+    }
+
+    fn tick(&mut self, now_us: u64, _params: &Params, _rng: &mut dyn RngCore) {
+        self.now_us = now_us;
+    }
+
+    fn handle_keypress(
+        &mut self,
+        key: Option<Position>,
+        map: &LedMap<N>,
+        params: &Params,
+        _rng: &mut dyn RngCore,
+    ) {
+        if let Some(pos) = key {
+            self.presses.add(pos, map, self.now_us, params);
+        }
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        *leds = [OFF; N];
+
+        for press in self.presses.iter() {
+            let age_us = self.now_us.saturating_sub(press.start_us);
+            let fade = fade_out(age_us, RIPPLE_US);
+            if fade == 0 {
+                continue;
+            }
+            let speed = RIPPLE_MIN_SPEED + params.speed as u64;
+            let radius = (age_us * speed / 1_000_000) as u16;
+
+            for (led, dist) in leds.iter_mut().zip(press.distances.iter()) {
+                let from_ring = if *dist > radius { dist - radius } else { radius - dist };
+                if from_ring >= RIPPLE_WIDTH {
+                    continue;
+                }
+                let level = (RIPPLE_WIDTH - from_ring) as u32 * fade as u32 / RIPPLE_WIDTH as u32;
+                add(led, scale(press.color, level as u8));
+            }
+        }
+    }
+}
+
+/// A splash of color around each pressed key, fading away
+pub struct Splash<const N: usize> {
+    presses: Presses<N>,
+    now_us: u64,
+}
+
+impl<const N: usize> Effect<N> for Splash<N> {
+    fn id(&self) -> &'static str {
+        "splash"
+    }
+
+    fn name(&self) -> &'static str {
+        "Splash"
+    }
+
+    fn init(&mut self) {
+        self.presses.clear();
+    }
+
+    fn tick(&mut self, now_us: u64, _params: &Params, _rng: &mut dyn RngCore) {
+        self.now_us = now_us;
+    }
+
+    fn handle_keypress(
+        &mut self,
+        key: Option<Position>,
+        map: &LedMap<N>,
+        params: &Params,
+        _rng: &mut dyn RngCore,
+    ) {
+        if let Some(pos) = key {
+            self.presses.add(pos, map, self.now_us, params);
+        }
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        *leds = [OFF; N];
+        let duration_us = params.period_us(1_200_000, 100_000);
+
+        for press in self.presses.iter() {
+            let fade = fade_out(self.now_us.saturating_sub(press.start_us), duration_us);
+            if fade == 0 {
+                continue;
+            }
+
+            for (led, dist) in leds.iter_mut().zip(press.distances.iter()) {
+                if *dist >= SPLASH_RADIUS {
+                    continue;
+                }
+                let level = (SPLASH_RADIUS - dist) as u32 * fade as u32 / SPLASH_RADIUS as u32;
+                add(led, scale(press.color, level as u8));
+            }
+        }
+    }
+}
+
+/// The LED under each key glows when it's pressed, dimming over a couple of
+/// seconds
+pub struct Glow<const N: usize> {
+    last_press_us: [Option<u64>; N],
+    now_us: u64,
+}
+
+impl<const N: usize> Effect<N> for Glow<N> {
+    fn id(&self) -> &'static str {
+        "glow"
+    }
+
+    fn name(&self) -> &'static str {
+        "Glow"
+    }
+
+    fn init(&mut self) {
+        self.last_press_us = [None; N];
+    }
+
+    fn tick(&mut self, now_us: u64, _params: &Params, _rng: &mut dyn RngCore) {
+        self.now_us = now_us;
+    }
+
+    fn handle_keypress(
+        &mut self,
+        key: Option<Position>,
+        map: &LedMap<N>,
+        _params: &Params,
+        _rng: &mut dyn RngCore,
+    ) {
+        if let Some(pos) = key {
+            self.last_press_us[map.nearest_leds::<1>(pos)[0]] = Some(self.now_us);
+        }
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        let duration_us = params.period_us(4_000_000, 500_000);
+        for (led, last_press_us) in leds.iter_mut().zip(self.last_press_us.iter()) {
+            *led = match last_press_us {
+                Some(t) => {
+                    let fade = fade_out(self.now_us.saturating_sub(*t), duration_us);
+                    scale(params.color(), fade)
+                }
+                None => OFF,
+            };
+        }
+    }
+}
+
+/// One color everywhere
+pub struct Solid<const N: usize>;
+
+impl<const N: usize> Effect<N> for Solid<N> {
+    fn id(&self) -> &'static str {
+        "solid"
+    }
+
+    fn name(&self) -> &'static str {
+        "Solid"
+    }
+
+    fn init(&mut self) {}
+
+    fn tick(&mut self, _now_us: u64, _params: &Params, _rng: &mut dyn RngCore) {}
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        *leds = [params.color(); N];
+    }
+}
+
+/// One color slowly fading in and out
+pub struct Breathing<const N: usize> {
+    now_us: u64,
+}
+
+impl<const N: usize> Effect<N> for Breathing<N> {
+    fn id(&self) -> &'static str {
+        "breathing"
+    }
+
+    fn name(&self) -> &'static str {
+        "Breathing"
+    }
+
+    fn init(&mut self) {}
+
+    fn tick(&mut self, now_us: u64, _params: &Params, _rng: &mut dyn RngCore) {
+        self.now_us = now_us;
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        let period_us = BREATHE_MIN_US + BREATHE_STEP_US * 255;
+        let period_us = params.period_us(period_us, BREATHE_MIN_US);
+        let phase = (self.now_us % period_us * 512 / period_us) as u32;
+        let triangle = if phase < 256 { phase } else { 511 - phase };
+        // Squared so it lingers near off, which looks more like breathing
+        let level = (triangle * triangle / 255) as u8;
+
+        *leds = [scale(params.color(), level); N];
+    }
+}
+
+/// Part of the color wheel spread across the strip, drifting along at
+/// anything above speed 0
+pub struct Gradient<const N: usize> {
+    offset: u8,
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Gradient<N> {
+    fn id(&self) -> &'static str {
+        "gradient"
+    }
+
+    fn name(&self) -> &'static str {
+        "Gradient"
+    }
+
+    fn init(&mut self) {
+        self.offset = 0;
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, _rng: &mut dyn RngCore) {
+        if params.speed == 0 {
+            return;
+        }
+        if self.interval.elapsed(now_us, params.period_us(200_000, 5_000)) {
+            self.offset = self.offset.wrapping_add(1);
+        }
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        for (i, led) in leds.iter_mut().enumerate() {
+            *led = params.color_at(self.offset.wrapping_add((i * GRADIENT_SPAN / N) as u8));
+        }
+    }
+}
+
+/// Flickering flames: random sparks of heat that spread to their neighbours
+/// and cool off
+pub struct Fire<const N: usize> {
+    heat: [u8; N],
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Fire<N> {
+    fn id(&self) -> &'static str {
+        "fire"
+    }
+
+    fn name(&self) -> &'static str {
+        "Fire"
+    }
+
+    fn init(&mut self) {
+        self.heat = [0; N];
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, rng: &mut dyn RngCore) {
+        if !self.interval.elapsed(now_us, params.period_us(100_000, 10_000)) {
+            return;
+        }
+
+        for heat in self.heat.iter_mut() {
+            *heat = heat.saturating_sub((rng.next_u32() % FIRE_COOLING) as u8);
+        }
+
+        let mut spread = self.heat;
+        for i in 0..N {
+            let left = self.heat[i.saturating_sub(1)] as u16;
+            let right = self.heat[(i + 1).min(N - 1)] as u16;
+            spread[i] = ((left + self.heat[i] as u16 * 2 + right) / 4) as u8;
+        }
+        self.heat = spread;
+
+        let heat = &mut self.heat[rand_index(rng, N)];
+        *heat = heat.saturating_add(100 + (rng.next_u32() % 155) as u8);
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        let color = params.color();
+        for (led, heat) in leds.iter_mut().zip(self.heat.iter()) {
+            *led = scale(color, *heat);
+            // The hottest parts burn towards white
+            if *heat > 200 {
+                let white = (*heat - 200) * 3;
+                add(led, RGB8 { r: white, g: white, b: white });
+            }
+        }
+    }
+}
+
+/// Stars twinkling on at random and slowly fading
+pub struct Starlight<const N: usize> {
+    levels: [u8; N],
+    interval: Interval,
+}
+
+impl<const N: usize> Effect<N> for Starlight<N> {
+    fn id(&self) -> &'static str {
+        "starlight"
+    }
+
+    fn name(&self) -> &'static str {
+        "Starlight"
+    }
+
+    fn init(&mut self) {
+        self.levels = [0; N];
+    }
+
+    fn tick(&mut self, now_us: u64, params: &Params, rng: &mut dyn RngCore) {
+        if !self.interval.elapsed(now_us, params.period_us(50_000, 5_000)) {
+            return;
+        }
+
+        for level in self.levels.iter_mut() {
+            *level = level.saturating_sub(STAR_FADE);
+        }
+        if one_in_chance(rng, STAR_CHANCE) {
+            let level = &mut self.levels[rand_index(rng, N)];
+            if *level == 0 {
+                *level = 255;
+            }
+        }
+    }
+
+    fn render(&self, params: &Params, leds: &mut [RGB8; N]) {
+        // Half the saturation, so stars stay pale whatever the hue
+        let color = hsv_rgb(params.hue, params.sat / 2, params.val);
+        for (led, level) in leds.iter_mut().zip(self.levels.iter()) {
+            *led = scale(color, *level);
+        }
+    }
+}
+
+/// Starting params of each effect, by index. `None` for the effects that can't
+/// be adjusted.
+pub const DEFAULT_PARAMS: [Option<Params>; COUNT] = [
+    Some(Params::new(0, 128)),
+    Some(Params::new(42, 128)),
+    Some(Params::new(0, 128)),
+    Some(Params::new(0, 128)),
+    None,
+    Some(Params::new(0, 128)),
+    Some(Params::new(0, 128)),
+    Some(Params {
+        hue: 18,
+        sat: 235,
+        val: 255,
+        speed: 128,
+    }),
+    Some(Params::new(160, 0)),
+    Some(Params::new(160, 128)),
+    Some(Params::new(0, 0)),
+    Some(Params::new(16, 160)),
+    Some(Params::new(170, 128)),
+];
+
+// Passed to the effects without params, which ignore them
+const NO_PARAMS: Params = Params::new(0, 0);
+
+/// Every effect, by index
+pub struct Effects<const N: usize> {
+    pub rainbow: Rainbow<N>,
+    pub lightning: Lightning<N>,
+    pub chase: Chase<N>,
+    pub chase_2: Chase2<N>,
+    pub heatmap: HeatmapEffect<N>,
+    pub ripple: Ripple<N>,
+    pub splash: Splash<N>,
+    pub glow: Glow<N>,
+    pub solid: Solid<N>,
+    pub breathing: Breathing<N>,
+    pub gradient: Gradient<N>,
+    pub fire: Fire<N>,
+    pub starlight: Starlight<N>,
+    params: [Option<Params>; COUNT],
+}
+
+impl<const N: usize> Effects<N> {
+    pub fn new() -> Self {
+        Self {
+            rainbow: Rainbow {
+                wheel_positions: [0; N],
+                interval: Interval::new(),
+            },
+            lightning: Lightning {
+                leds: [OFF; N],
+                interval: Interval::new(),
+            },
+            chase: Chase {
+                leds: [OFF; N],
+                position: 0,
+                wheel_pos: 0,
+                interval: Interval::new(),
+            },
+            chase_2: Chase2 {
+                leds: [OFF; N],
+                position: 0,
+                wheel_pos: 0,
+                interval: Interval::new(),
+            },
+            heatmap: HeatmapEffect { levels: [0; N] },
+            ripple: Ripple {
+                presses: Presses::new(),
+                now_us: 0,
+            },
+            splash: Splash {
+                presses: Presses::new(),
+                now_us: 0,
+            },
+            glow: Glow {
+                last_press_us: [None; N],
+                now_us: 0,
+            },
+            solid: Solid,
+            breathing: Breathing {
+                now_us: 0,
+            },
+            gradient: Gradient {
+                offset: 0,
+                interval: Interval::new(),
+            },
+            fire: Fire {
+                heat: [0; N],
+                interval: Interval::new(),
+            },
+            starlight: Starlight {
+                levels: [0; N],
+                interval: Interval::new(),
+            },
+            params: DEFAULT_PARAMS,
+        }
+    }
+
+    /// The effect at `index`, which must be below `COUNT`. Anything else is a
+    /// bug, caught in debug builds and falling back to the rainbow otherwise.
+    pub fn get(&self, index: usize) -> &dyn Effect<N> {
+        match index {
+            RAINBOW => &self.rainbow,
+            LIGHTNING => &self.lightning,
+            CHASE => &self.chase,
+            CHASE_2 => &self.chase_2,
+            HEATMAP => &self.heatmap,
+            RIPPLE => &self.ripple,
+            SPLASH => &self.splash,
+            GLOW => &self.glow,
+            SOLID => &self.solid,
+            BREATHING => &self.breathing,
+            GRADIENT => &self.gradient,
+            FIRE => &self.fire,
+            STARLIGHT => &self.starlight,
+            _ => {
+                debug_assert!(false, "no effect {}", index);
+                &self.rainbow
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut dyn Effect<N> {
+        match index {
+            RAINBOW => &mut self.rainbow,
+            LIGHTNING => &mut self.lightning,
+            CHASE => &mut self.chase,
+            CHASE_2 => &mut self.chase_2,
+            HEATMAP => &mut self.heatmap,
+            RIPPLE => &mut self.ripple,
+            SPLASH => &mut self.splash,
+            GLOW => &mut self.glow,
+            SOLID => &mut self.solid,
+            BREATHING => &mut self.breathing,
+            GRADIENT => &mut self.gradient,
+            FIRE => &mut self.fire,
+            STARLIGHT => &mut self.starlight,
+            _ => {
+                debug_assert!(false, "no effect {}", index);
+                &mut self.rainbow
+            }
+        }
+    }
+
+    /// Speed and color of the effect at `index`, if it can be adjusted
+    pub fn params(&self, index: usize) -> Option<Params> {
+        self.params.get(index).copied().flatten()
+    }
+
+    /// Ignored for effects that can't be adjusted
+    pub fn set_params(&mut self, index: usize, params: Params) {
+        if let Some(Some(p)) = self.params.get_mut(index) {
+            *p = params;
+        }
+    }
+
+    // What the effect at `index` is given to work with
+    fn params_for(&self, index: usize) -> Params {
+        self.params(index).unwrap_or(NO_PARAMS)
+    }
+
+    pub fn tick(&mut self, index: usize, now_us: u64, rng: &mut dyn RngCore) {
+        let params = self.params_for(index);
+        self.get_mut(index).tick(now_us, &params, rng);
+    }
+
+    pub fn handle_keypress(
+        &mut self,
+        index: usize,
+        key: Option<Position>,
+        map: &LedMap<N>,
+        rng: &mut dyn RngCore,
+    ) {
+        let params = self.params_for(index);
+        self.get_mut(index).handle_keypress(key, map, &params, rng);
+    }
+
+    pub fn render(&self, index: usize, leds: &mut [RGB8; N]) {
+        self.get(index).render(&self.params_for(index), leds);
+    }
+
+    /// Index of the effect with `id`
+    pub fn find(&self, id: &str) -> Option<usize> {
+        (0..COUNT).find(|&i| self.get(i).id() == id)
+    }
+}
@@ -0,0 +1,86 @@
+//! Raw access to the end of the onboard QSPI flash, used for persistent
+//! storage. The region is excluded from the firmware image in `memory.x`.
+//!
+//! Erasing and programming are done through the bootrom flash functions. XIP
+//! is unavailable while they run, so everything between leaving and
+//! re-entering XIP executes from RAM with interrupts disabled. Afterwards the
+//! copy of boot2 is re-run to put the flash back into its fast read mode.
+
+use core::mem;
+
+pub const XIP_BASE: u32 = 0x1000_0000;
+pub const FLASH_SIZE: u32 = 2048 * 1024;
+pub const SECTOR_SIZE: u32 = 4096;
+pub const PAGE_SIZE: u32 = 256;
+
+/// Storage sectors, counting back from the end of flash. Keep in sync with
+/// the FLASH length in `memory.x`.
+pub const SETTINGS_OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE;
+pub const HEATMAP_OFFSET: u32 = FLASH_SIZE - 2 * SECTOR_SIZE;
+
+const BOOT2_SIZE_WORDS: usize = 64;
+static mut BOOT2_COPY: [u32; BOOT2_SIZE_WORDS] = [0; BOOT2_SIZE_WORDS];
+
+struct RomFunctions {
+    connect_internal_flash: extern "C" fn(),
+    flash_exit_xip: extern "C" fn(),
+    flash_range_erase: extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: extern "C" fn(),
+    boot2: extern "C" fn(),
+}
+
+/// Look up a function in the bootrom function table by its two letter tag.
+unsafe fn rom_func(tag: &[u8; 2]) -> usize {
+    let lookup: extern "C" fn(*const u16, u32) -> usize =
+        mem::transmute(*(0x18 as *const u16) as usize);
+    let table = *(0x14 as *const u16) as *const u16;
+    lookup(table, u16::from_le_bytes(*tag) as u32)
+}
+
+unsafe fn rom_functions() -> RomFunctions {
+    // boot2 lives at the start of flash, which is about to become unreadable
+    let boot2 = &mut *core::ptr::addr_of_mut!(BOOT2_COPY);
+    core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), BOOT2_SIZE_WORDS);
+
+    RomFunctions {
+        connect_internal_flash: mem::transmute(rom_func(b"IF")),
+        flash_exit_xip: mem::transmute(rom_func(b"EX")),
+        flash_range_erase: mem::transmute(rom_func(b"RE")),
+        flash_range_program: mem::transmute(rom_func(b"RP")),
+        flash_flush_cache: mem::transmute(rom_func(b"FC")),
+        // +1 for the thumb bit
+        boot2: mem::transmute((boot2.as_ptr() as *const u8).add(1)),
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn erase_and_program_ram(rom: &RomFunctions, offset: u32, data: *const u8, len: usize) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    // 4k sector erase
+    (rom.flash_range_erase)(offset, SECTOR_SIZE as usize, SECTOR_SIZE, 0x20);
+    (rom.flash_range_program)(offset, data, len);
+    (rom.flash_flush_cache)();
+    (rom.boot2)();
+}
+
+/// Read `len` bytes at `offset` from the start of flash.
+pub fn read(offset: u32, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((XIP_BASE + offset) as *const u8, len) }
+}
+
+/// Erase the sector at `offset` and program `data` into it. `data` must be a
+/// whole number of pages, fit within the sector, and live in RAM (flash can't
+/// be read while it is being programmed). This blocks for tens of
+/// milliseconds with interrupts disabled.
+pub fn write_sector(offset: u32, data: &[u8]) {
+    assert!(offset % SECTOR_SIZE == 0);
+    assert!(data.len() % PAGE_SIZE as usize == 0 && data.len() <= SECTOR_SIZE as usize);
+
+    cortex_m::interrupt::free(|_cs| unsafe {
+        let rom = rom_functions();
+        erase_and_program_ram(&rom, offset, data.as_ptr(), data.len());
+    });
+}
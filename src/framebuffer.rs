@@ -0,0 +1,166 @@
+//! Frame buffer for the SSD1306 with dirty tracking.
+//!
+//! Pages draw into `buffer`, laid out the same as the SSD1306 GDDRAM: one
+//! byte per column per 8-pixel page. A copy of what has been sent to the
+//! panel is kept in `sent`, so only the bytes that actually changed need to
+//! go over I2C. The transfer is split into small chunks so that a single
+//! update never holds up key scanning for long.
+//!
+//! For panels rotated by 90 or 270 degrees the drawing coordinates are
+//! transposed, matching the controller's scan direction set up by `ssd1306`.
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+const MAX_WIDTH: usize = 128;
+const MAX_HEIGHT: usize = 64;
+const BUFFER_LEN: usize = MAX_WIDTH * MAX_HEIGHT / 8;
+
+/// Largest number of data bytes sent in one chunk
+pub const MAX_CHUNK_LEN: usize = 32;
+
+/// A run of changed bytes within one page
+pub struct Chunk {
+    pub page: u8,
+    pub column: u8,
+    data: [u8; MAX_CHUNK_LEN],
+    len: usize,
+}
+
+impl Chunk {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+pub struct FrameBuffer {
+    buffer: [u8; BUFFER_LEN],
+    sent: [u8; BUFFER_LEN],
+    // Physical panel size
+    width: usize,
+    height: usize,
+    transposed: bool,
+    // Page to resume searching for changes from, so the whole frame gets a
+    // fair share of chunks
+    cursor: usize,
+}
+
+impl FrameBuffer {
+    /// `width` and `height` are the physical panel size, `transposed` is set
+    /// for panels rotated by 90 or 270 degrees.
+    pub fn new(width: usize, height: usize, transposed: bool) -> Self {
+        let mut fb = Self {
+            buffer: [0; BUFFER_LEN],
+            sent: [0; BUFFER_LEN],
+            width: width.min(MAX_WIDTH),
+            height: height.min(MAX_HEIGHT),
+            transposed,
+            cursor: 0,
+        };
+        fb.invalidate();
+        return fb;
+    }
+
+    fn pages(&self) -> usize {
+        self.height / 8
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer = [0; BUFFER_LEN];
+    }
+
+    /// Forget what is on the panel, so the whole frame gets sent again
+    pub fn invalidate(&mut self) {
+        for (sent, byte) in self.sent.iter_mut().zip(self.buffer.iter()) {
+            *sent = !*byte;
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        let len = self.width * self.pages();
+        self.buffer[..len] != self.sent[..len]
+    }
+
+    /// Take the next run of changed bytes, up to `max_len` long, and assume it
+    /// has been sent to the panel.
+    pub fn take_chunk(&mut self, max_len: usize) -> Option<Chunk> {
+        let max_len = max_len.min(MAX_CHUNK_LEN).max(1);
+        let pages = self.pages();
+
+        for n in 0..pages {
+            let page = (self.cursor + n) % pages;
+            let start = page * self.width;
+            let row = start..start + self.width;
+
+            let first = match row.clone().find(|&i| self.buffer[i] != self.sent[i]) {
+                Some(first) => first,
+                None => continue,
+            };
+            // Runs up to the last change within reach, including any unchanged
+            // bytes in between; a few extra bytes beat another address setup.
+            let end = (first + max_len).min(row.end);
+            let last = (first..end)
+                .rev()
+                .find(|&i| self.buffer[i] != self.sent[i])
+                .unwrap_or(first);
+
+            let len = last + 1 - first;
+            let mut chunk = Chunk {
+                page: page as u8,
+                column: (first - start) as u8,
+                data: [0; MAX_CHUNK_LEN],
+                len,
+            };
+            chunk.data[..len].copy_from_slice(&self.buffer[first..=last]);
+            self.sent[first..=last].copy_from_slice(&self.buffer[first..=last]);
+
+            self.cursor = page;
+            return Some(chunk);
+        }
+
+        None
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        if self.transposed {
+            Size::new(self.height as u32, self.width as u32)
+        } else {
+            Size::new(self.width as u32, self.height as u32)
+        }
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<P>(&mut self, pixels: P) -> Result<(), Self::Error>
+    where
+        P: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let point = if self.transposed {
+                Point::new(point.y, point.x)
+            } else {
+                point
+            };
+            if point.x < 0
+                || point.y < 0
+                || point.x as usize >= self.width
+                || point.y as usize >= self.height
+            {
+                continue;
+            }
+
+            let (x, y) = (point.x as usize, point.y as usize);
+            let index = (y / 8) * self.width + x;
+            let bit = 1 << (y % 8);
+            match color {
+                BinaryColor::On => self.buffer[index] |= bit,
+                BinaryColor::Off => self.buffer[index] &= !bit,
+            }
+        }
+        Ok(())
+    }
+}
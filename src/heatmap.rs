@@ -0,0 +1,180 @@
+//! Per-key press counters.
+//!
+//! Counts debounced presses for every matrix position, to gather data on real
+//! key usage for tuning the layout and tracking switch wear. Counts are
+//! snapshotted to flash periodically, and can be exported to the host or
+//! rendered on the LED strip.
+//!
+//! A full export can be bigger than the host channel's output buffer, so it's
+//! written a row at a time as the host reads it.
+
+use crate::flash;
+use crate::led_map::{LedMap, Position};
+use core::fmt;
+
+const MAGIC: [u8; 4] = *b"HEAT";
+const STORED_LEN: usize = 2 * flash::PAGE_SIZE as usize;
+
+// Snapshot at most every 15 minutes while keys are being pressed
+const SNAPSHOT_INTERVAL_TICKS: u32 = 15 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HeatmapFormat {
+    Csv,
+    Json,
+}
+
+pub struct Heatmap<const CS: usize, const RS: usize> {
+    counts: [[u32; CS]; RS],
+    dirty: bool,
+    ticks_since_snapshot: u32,
+    /// Format and next row of the export in progress
+    export: Option<(HeatmapFormat, usize)>,
+}
+
+impl<const CS: usize, const RS: usize> Heatmap<CS, RS> {
+    /// Most output written by one `export_next`: a row of 10 digit counts,
+    /// with room to spare for the JSON header and punctuation
+    pub const MAX_EXPORT_PIECE_LEN: usize = 64 + CS * 11;
+
+    /// Load the last snapshot from flash, or start from zero.
+    pub fn load() -> Self {
+        let mut heatmap = Self {
+            counts: [[0; CS]; RS],
+            dirty: false,
+            ticks_since_snapshot: 0,
+            export: None,
+        };
+
+        let stored = flash::read(flash::HEATMAP_OFFSET, STORED_LEN);
+        let len = CS * RS * 4;
+        let payload = &stored[4..4 + len];
+        if stored[..4] == MAGIC && checksum(payload) == stored[4 + len..4 + len + 4] {
+            for (k, bytes) in payload.chunks_exact(4).enumerate() {
+                heatmap.counts[k / CS][k % CS] =
+                    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+        }
+
+        return heatmap;
+    }
+
+    pub fn handle_keypress(&mut self, row: usize, col: usize) {
+        self.counts[row][col] = self.counts[row][col].saturating_add(1);
+        self.dirty = true;
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = [[0; CS]; RS];
+        self.dirty = true;
+    }
+
+    /// Called once per scan. Returns true when a snapshot is due.
+    pub fn tick(&mut self) -> bool {
+        self.ticks_since_snapshot = self.ticks_since_snapshot.saturating_add(1);
+        return self.ticks_since_snapshot >= SNAPSHOT_INTERVAL_TICKS;
+    }
+
+    /// Write the counts to flash, if they changed since the last snapshot.
+    /// This blocks for tens of milliseconds.
+    pub fn snapshot(&mut self) {
+        self.ticks_since_snapshot = 0;
+        if !self.dirty {
+            return;
+        }
+
+        let mut stored = [0xFFu8; STORED_LEN];
+        stored[..4].copy_from_slice(&MAGIC);
+        let len = CS * RS * 4;
+        for (k, bytes) in stored[4..4 + len].chunks_exact_mut(4).enumerate() {
+            bytes.copy_from_slice(&self.counts[k / CS][k % CS].to_le_bytes());
+        }
+        let sum = checksum(&stored[4..4 + len]);
+        stored[4 + len..4 + len + 4].copy_from_slice(&sum);
+
+        flash::write_sector(flash::HEATMAP_OFFSET, &stored);
+        self.dirty = false;
+    }
+
+    /// Relative usage around each LED, scaled so the busiest LED is 255. The
+    /// keys sit between LEDs, so each one counts towards the two nearest.
+    pub fn led_levels<const NUM_LEDS: usize>(&self, map: &LedMap<NUM_LEDS>) -> [u8; NUM_LEDS] {
+        let mut totals = [0u32; NUM_LEDS];
+        for (row, counts) in self.counts.iter().enumerate() {
+            for (col, count) in counts.iter().enumerate() {
+                for led in map.nearest_leds::<2>(Position::of_key(row, col)) {
+                    totals[led] = totals[led].saturating_add(*count);
+                }
+            }
+        }
+
+        let max = totals.iter().copied().max().unwrap_or(0).max(1);
+        let mut levels = [0u8; NUM_LEDS];
+        for (level, total) in levels.iter_mut().zip(totals.iter()) {
+            *level = (*total as u64 * 255 / max as u64) as u8;
+        }
+
+        return levels;
+    }
+
+    /// Start exporting the counts as CSV (one line per row, then "end") or as
+    /// a JSON object. The export is written out by `export_next`.
+    pub fn start_export(&mut self, format: HeatmapFormat) {
+        self.export = Some((format, 0));
+    }
+
+    pub fn is_exporting(&self) -> bool {
+        self.export.is_some()
+    }
+
+    /// Write the next row of the export in progress, at most
+    /// `MAX_EXPORT_PIECE_LEN` bytes.
+    pub fn export_next<W: fmt::Write>(&mut self, w: &mut W) -> fmt::Result {
+        let (format, row) = match self.export {
+            Some(export) => export,
+            None => return Ok(()),
+        };
+        self.export = Some((format, row + 1)).filter(|_| row < RS);
+
+        match format {
+            HeatmapFormat::Csv => {
+                if row == RS {
+                    return w.write_str("end\r\n");
+                }
+                self.write_row(w, row)?;
+                w.write_str("\r\n")
+            }
+            HeatmapFormat::Json => {
+                if row == 0 {
+                    write!(w, "{{\"rows\":{},\"cols\":{},\"counts\":[", RS, CS)?;
+                }
+                if row == RS {
+                    return w.write_str("]}\r\n");
+                }
+                if row > 0 {
+                    w.write_char(',')?;
+                }
+                w.write_char('[')?;
+                self.write_row(w, row)?;
+                w.write_char(']')
+            }
+        }
+    }
+
+    fn write_row<W: fmt::Write>(&self, w: &mut W, row: usize) -> fmt::Result {
+        for (col, count) in self.counts[row].iter().enumerate() {
+            if col > 0 {
+                w.write_char(',')?;
+            }
+            write!(w, "{}", count)?;
+        }
+        Ok(())
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 4] {
+    bytes
+        .iter()
+        .fold(0u32, |acc, b| acc.rotate_left(5) ^ *b as u32)
+        .to_le_bytes()
+}
@@ -0,0 +1,432 @@
+//! Host channel over USB CDC serial.
+//!
+//! A simple line based protocol: the host sends one command per line, and
+//! replies, reports and debug output come back as lines of text. Commands are
+//! parsed in the USB interrupt and handed to the `host_command` task, while
+//! output is buffered here and drained whenever the serial port has room.
+
+use crate::clock::{MAX_UNIX_SECS, MAX_UTC_OFFSET_SECS};
+use crate::compositor::{Blend, Slot, Style};
+use crate::heatmap::HeatmapFormat;
+use crate::images;
+use crate::led_output::{Calibration, MAX_CURRENT_BUDGET_MA};
+use crate::led_state::{MAX_LAYERS, MAX_TRANSITION_MS};
+use crate::notify::{Notification, MAX_PRIORITY};
+use smart_leds::RGB8;
+use core::fmt;
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usbd_serial::SerialPort;
+
+const LINE_LEN: usize = 128;
+/// Most colors a `frame` line can hold, 6 hex digits each
+pub const MAX_FRAME_LEDS: usize = (LINE_LEN - "frame ".len()) / 6;
+const TX_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HostCommand {
+    /// Report the worst chattering/bouncing switches
+    Diagnostics,
+    ResetDiagnostics,
+    /// Dump the per-key press counters
+    ExportHeatmap(HeatmapFormat),
+    ResetHeatmap,
+    /// Show or update a notification on the OLED
+    Notify(Notification),
+    /// Remove one notification, or all of them
+    ClearNotification(Option<u8>),
+    /// Report the wall clock time
+    Time,
+    /// Set the wall clock
+    SetTime { unix_secs: i64, utc_offset_secs: i32 },
+    /// Volume level in percent, for the media key overlay
+    Volume(u8),
+    /// Play one of the `images` animations, by index into `images::ALL`
+    Animation(usize),
+    /// Report the LED effect, or select one by id or index
+    Effect(Option<heapless::String<16>>),
+    /// Report the reactive LED effect, or select one by id or index, or
+    /// "off"
+    Reactive(Option<heapless::String<16>>),
+    /// Report the crossfade time between LED effects, or set it in ms
+    Transition(Option<u16>),
+    /// Report or set the blend mode and opacity of a level of the LED stack
+    Blend { slot: Slot, style: Option<Style> },
+    /// Set or clear the LED overlay color for a layer
+    LayerColor { layer: usize, color: Option<RGB8> },
+    /// Set one LED from the host, taking over from the effects
+    SetLed { index: usize, color: RGB8 },
+    /// Set LEDs from the first one on, taking over from the effects. Not
+    /// replied to, so frames can be streamed.
+    Frame(heapless::Vec<RGB8, MAX_FRAME_LEDS>),
+    /// Hand the LEDs back to the effects
+    ReleaseLeds,
+    /// Report the LED brightness, or set it
+    Brightness(Option<u8>),
+    /// Report the LED white balance, or set it
+    Calibrate(Option<Calibration>),
+    /// Report the LED current draw, or set the budget in mA
+    Power(Option<u16>),
+    /// Report the current effect's parameters, or change some of them
+    Params {
+        hue: Option<u8>,
+        sat: Option<u8>,
+        val: Option<u8>,
+        speed: Option<u8>,
+    },
+}
+
+const NOTIFY_USAGE: &str =
+    "usage: notify <id> <priority 0-9> <seconds, 0 = forever> [icon=<name>] [progress=<0-100>] <text> | notify clear [id]";
+
+/// Split off the first word, returning it and the rest of the line
+fn next_word(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    line.split_once(' ').unwrap_or((line, ""))
+}
+
+// "rrggbb" hex, repeated
+fn parse_frame(hex: &str) -> Option<heapless::Vec<RGB8, MAX_FRAME_LEDS>> {
+    if hex.is_empty() || hex.len() % 6 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    let mut ret = heapless::Vec::new();
+    for i in (0..hex.len()).step_by(6) {
+        let channel = |at: usize| u8::from_str_radix(&hex[i + at..i + at + 2], 16).ok();
+        let color = RGB8 {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        };
+        ret.push(color).ok()?;
+    }
+    return Some(ret);
+}
+
+fn parse_notify(args: &str) -> Result<HostCommand, &'static str> {
+    let (first, rest) = next_word(args);
+    if first == "clear" {
+        return match next_word(rest).0 {
+            "" => Ok(HostCommand::ClearNotification(None)),
+            id => id
+                .parse()
+                .map(|id| HostCommand::ClearNotification(Some(id)))
+                .map_err(|_| NOTIFY_USAGE),
+        };
+    }
+
+    let id = first.parse().map_err(|_| NOTIFY_USAGE)?;
+    let (priority, rest) = next_word(rest);
+    let priority: u8 = priority.parse().map_err(|_| NOTIFY_USAGE)?;
+    if priority > MAX_PRIORITY {
+        return Err(NOTIFY_USAGE);
+    }
+    let (secs, mut rest) = next_word(rest);
+    let secs: u32 = secs.parse().map_err(|_| NOTIFY_USAGE)?;
+
+    let mut notification = Notification {
+        id,
+        priority,
+        expires_us: if secs == 0 { None } else { Some(secs as u64 * 1_000_000) },
+        icon: None,
+        progress: None,
+        text: heapless::String::new(),
+    };
+
+    // Options come before the text
+    loop {
+        let (word, after) = next_word(rest);
+        if let Some(name) = word.strip_prefix("icon=") {
+            notification.icon = Some(
+                images::ALL
+                    .iter()
+                    .position(|a| a.name == name)
+                    .ok_or("unknown icon")?,
+            );
+        } else if let Some(percent) = word.strip_prefix("progress=") {
+            let percent: u8 = percent.parse().map_err(|_| NOTIFY_USAGE)?;
+            notification.progress = Some(percent.min(100));
+        } else {
+            break;
+        }
+        rest = after;
+    }
+
+    // Cut long text to fit
+    for c in rest.trim().chars() {
+        if notification.text.push(c).is_err() {
+            break;
+        }
+    }
+    Ok(HostCommand::Notify(notification))
+}
+
+impl HostCommand {
+    fn parse(line: &str) -> Result<Self, &'static str> {
+        let mut args = line.split_whitespace();
+
+        match args.next() {
+            Some("diag") => match args.next() {
+                None => Ok(HostCommand::Diagnostics),
+                Some("reset") => Ok(HostCommand::ResetDiagnostics),
+                _ => Err("usage: diag [reset]"),
+            },
+            Some("heatmap") => match args.next() {
+                None | Some("csv") => Ok(HostCommand::ExportHeatmap(HeatmapFormat::Csv)),
+                Some("json") => Ok(HostCommand::ExportHeatmap(HeatmapFormat::Json)),
+                Some("reset") => Ok(HostCommand::ResetHeatmap),
+                _ => Err("usage: heatmap [csv|json|reset]"),
+            },
+            Some("time") => match (args.next(), args.next()) {
+                (None, _) => Ok(HostCommand::Time),
+                (Some(secs), offset) => {
+                    let usage = "usage: time [<unix seconds> [<utc offset minutes>]]";
+                    let unix_secs = secs
+                        .parse()
+                        .ok()
+                        .filter(|s| (0..=MAX_UNIX_SECS).contains(s))
+                        .ok_or(usage)?;
+                    let offset_mins: i32 = offset
+                        .map_or(Ok(0), |o| o.parse())
+                        .ok()
+                        .filter(|m: &i32| m.abs() <= MAX_UTC_OFFSET_SECS / 60)
+                        .ok_or(usage)?;
+                    Ok(HostCommand::SetTime {
+                        unix_secs,
+                        utc_offset_secs: offset_mins * 60,
+                    })
+                }
+            },
+            Some("volume") => args
+                .next()
+                .and_then(|v| v.parse::<u8>().ok())
+                .filter(|v| *v <= 100)
+                .map(HostCommand::Volume)
+                .ok_or("usage: volume <0-100>"),
+            Some("anim") => {
+                let name = args.next().ok_or("usage: anim <name>")?;
+                images::ALL
+                    .iter()
+                    .position(|a| a.name == name)
+                    .map(HostCommand::Animation)
+                    .ok_or("unknown animation")
+            }
+            Some("effect") => match args.next() {
+                None => Ok(HostCommand::Effect(None)),
+                Some(id) => {
+                    let mut s = heapless::String::new();
+                    s.push_str(id).map_err(|_| "unknown effect")?;
+                    Ok(HostCommand::Effect(Some(s)))
+                }
+            },
+            Some("reactive") => match args.next() {
+                None => Ok(HostCommand::Reactive(None)),
+                Some(id) => {
+                    let mut s = heapless::String::new();
+                    s.push_str(id).map_err(|_| "unknown effect")?;
+                    Ok(HostCommand::Reactive(Some(s)))
+                }
+            },
+            Some("transition") => match args.next() {
+                None => Ok(HostCommand::Transition(None)),
+                Some(ms) => ms
+                    .parse()
+                    .ok()
+                    .filter(|ms| *ms <= MAX_TRANSITION_MS)
+                    .map(|ms| HostCommand::Transition(Some(ms)))
+                    .ok_or("usage: transition [<0-10000 ms>]"),
+            },
+            Some("blend") => {
+                let usage = "usage: blend <base|reactive|tint|indicators> [<normal|add|multiply|screen|lighten> <opacity 0-255>]";
+                let slot = args.next().and_then(Slot::find).ok_or(usage)?;
+                let style = match args.next() {
+                    None => None,
+                    Some(blend) => Some(Style {
+                        blend: Blend::find(blend).ok_or(usage)?,
+                        opacity: args.next().and_then(|o| o.parse().ok()).ok_or(usage)?,
+                    }),
+                };
+                Ok(HostCommand::Blend { slot, style })
+            }
+            Some("layercolor") => {
+                let usage = "usage: layercolor <layer> (<r> <g> <b> | off)";
+                let layer = args
+                    .next()
+                    .and_then(|l| l.parse().ok())
+                    .filter(|l| *l < MAX_LAYERS)
+                    .ok_or(usage)?;
+                let color = match args.next() {
+                    Some("off") => None,
+                    Some(r) => {
+                        let channel = |c: Option<&str>| c.and_then(|c| c.parse::<u8>().ok()).ok_or(usage);
+                        Some(RGB8 {
+                            r: channel(Some(r))?,
+                            g: channel(args.next())?,
+                            b: channel(args.next())?,
+                        })
+                    }
+                    None => return Err(usage),
+                };
+                Ok(HostCommand::LayerColor { layer, color })
+            }
+            Some("led") => {
+                let usage = "usage: led <index> <r> <g> <b>";
+                let index = args.next().and_then(|i| i.parse().ok()).ok_or(usage)?;
+                let mut channel = || args.next().and_then(|c| c.parse::<u8>().ok()).ok_or(usage);
+                let color = RGB8 {
+                    r: channel()?,
+                    g: channel()?,
+                    b: channel()?,
+                };
+                Ok(HostCommand::SetLed { index, color })
+            }
+            Some("frame") => args
+                .next()
+                .and_then(parse_frame)
+                .map(HostCommand::Frame)
+                .ok_or("usage: frame <rrggbb>..."),
+            Some("release") => Ok(HostCommand::ReleaseLeds),
+            Some("brightness") => match args.next() {
+                None => Ok(HostCommand::Brightness(None)),
+                Some(b) => b
+                    .parse()
+                    .map(|b| HostCommand::Brightness(Some(b)))
+                    .map_err(|_| "usage: brightness [<0-255>]"),
+            },
+            Some("calibrate") => match args.next() {
+                None => Ok(HostCommand::Calibrate(None)),
+                Some(r) => {
+                    let usage = "usage: calibrate [<r> <g> <b>]";
+                    let channel = |c: Option<&str>| c.and_then(|c| c.parse::<u8>().ok()).ok_or(usage);
+                    Ok(HostCommand::Calibrate(Some(Calibration {
+                        r: channel(Some(r))?,
+                        g: channel(args.next())?,
+                        b: channel(args.next())?,
+                    })))
+                }
+            },
+            Some("params") => {
+                let usage = "usage: params [hue=<0-255>] [sat=<0-255>] [val=<0-255>] [speed=<0-255>]";
+                let (mut hue, mut sat, mut val, mut speed) = (None, None, None, None);
+                for arg in args {
+                    let (name, value) = arg.split_once('=').ok_or(usage)?;
+                    let value = value.parse().map_err(|_| usage)?;
+                    match name {
+                        "hue" => hue = Some(value),
+                        "sat" => sat = Some(value),
+                        "val" => val = Some(value),
+                        "speed" => speed = Some(value),
+                        _ => return Err(usage),
+                    }
+                }
+                Ok(HostCommand::Params { hue, sat, val, speed })
+            }
+            Some("power") => match args.next() {
+                None => Ok(HostCommand::Power(None)),
+                Some(ma) => ma
+                    .parse()
+                    .ok()
+                    .filter(|ma| *ma <= MAX_CURRENT_BUDGET_MA)
+                    .map(|ma| HostCommand::Power(Some(ma)))
+                    .ok_or("usage: power [<budget mA>]"),
+            },
+            Some("notify") => parse_notify(line.trim_start().strip_prefix("notify").unwrap_or("")),
+            _ => Err("unknown command"),
+        }
+    }
+}
+
+pub struct HostChannel<B: UsbBus + 'static> {
+    serial: SerialPort<'static, B>,
+    line: heapless::Vec<u8, LINE_LEN>,
+    line_overflow: bool,
+    tx: heapless::Vec<u8, TX_LEN>,
+}
+
+impl<B: UsbBus> HostChannel<B> {
+    pub fn new(bus: &'static UsbBusAllocator<B>) -> Self {
+        Self {
+            serial: SerialPort::new(bus),
+            line: heapless::Vec::new(),
+            line_overflow: false,
+            tx: heapless::Vec::new(),
+        }
+    }
+
+    pub fn serial(&mut self) -> &mut SerialPort<'static, B> {
+        &mut self.serial
+    }
+
+    /// Read whatever the host has sent, calling `f` for every complete
+    /// command. Lines that fail to parse are answered with an error here, as
+    /// are commands `f` returns false for because it has no room for them.
+    pub fn poll_commands<F: FnMut(HostCommand) -> bool>(&mut self, mut f: F) {
+        let mut buf = [0u8; 64];
+
+        while let Ok(count) = self.serial.read(&mut buf) {
+            if count == 0 {
+                break;
+            }
+
+            for &byte in buf[..count].iter() {
+                if byte != b'\n' && byte != b'\r' {
+                    if self.line.push(byte).is_err() {
+                        self.line_overflow = true;
+                    }
+                    continue;
+                }
+
+                if self.line_overflow {
+                    self.reply(format_args!("err line too long"));
+                } else if let Ok(line) = core::str::from_utf8(&self.line) {
+                    if !line.trim().is_empty() {
+                        match HostCommand::parse(line) {
+                            Ok(command) => {
+                                if !f(command) {
+                                    self.reply(format_args!("err busy"));
+                                }
+                            }
+                            Err(e) => self.reply(format_args!("err {}", e)),
+                        }
+                    }
+                }
+
+                self.line.clear();
+                self.line_overflow = false;
+            }
+        }
+
+        self.flush();
+    }
+
+    /// Queue a line of output for the host. Output is dropped if nobody is
+    /// reading it and the buffer fills up.
+    pub fn reply(&mut self, args: fmt::Arguments) {
+        let _ = fmt::write(self, args);
+        let _ = fmt::Write::write_str(self, "\r\n");
+        self.flush();
+    }
+
+    /// Room left in the output buffer
+    pub fn tx_space(&self) -> usize {
+        self.tx.capacity() - self.tx.len()
+    }
+
+    /// Push as much buffered output as the serial port will take.
+    pub fn flush(&mut self) {
+        if self.tx.is_empty() {
+            return;
+        }
+
+        if let Ok(count) = self.serial.write(&self.tx) {
+            let remaining = self.tx.len() - count;
+            self.tx.copy_within(count.., 0);
+            self.tx.truncate(remaining);
+        }
+    }
+}
+
+impl<B: UsbBus> fmt::Write for HostChannel<B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.tx.extend_from_slice(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
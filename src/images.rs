@@ -0,0 +1,6 @@
+//! Images and animations, generated from `images/` by `build.rs`.
+
+use crate::animation::{Animation, Frame};
+use embedded_graphics::prelude::Size;
+
+include!(concat!(env!("OUT_DIR"), "/images.rs"));
@@ -49,7 +49,7 @@ const REPORT_DESCRIPTOR : &[u8] = &[
     0xC0,
 ];
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
 pub enum MediaKey {
     Record = 0x0B2,
@@ -66,13 +66,36 @@ pub enum MediaKey {
     VolDown = 0x0EA,
 }
 
+/// Keyboard LED state, as set by the host through the output report
+#[derive(Clone, Copy, Default, Eq, PartialEq)]
+pub struct KeyboardLeds(u8);
+
+impl KeyboardLeds {
+    pub fn num_lock(&self) -> bool {
+        self.0 & 0x01 != 0
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    pub fn scroll_lock(&self) -> bool {
+        self.0 & 0x04 != 0
+    }
+}
+
 #[derive(Default)]
 pub struct MediaKeyboard {
     media_report: MediaKeyHidReport,
     kb_report: KbHidReport,
+    leds: KeyboardLeds,
 }
 
 impl MediaKeyboard {
+    pub fn leds(&self) -> KeyboardLeds {
+        self.leds
+    }
+
     pub fn set_media_report(&mut self, report: MediaKeyHidReport) -> bool {
         if report == self.media_report {
             false
@@ -117,10 +140,18 @@ impl HidDevice for MediaKeyboard {
 
     fn set_report(
         &mut self,
-        _report_type: ReportType,
+        report_type: ReportType,
         _report_id: u8,
-        _data: &[u8],
+        data: &[u8],
     ) -> Result<(), ()> {
+        // The LED output report belongs to the keyboard collection (report
+        // ID 1), which the host may or may not prefix the data with.
+        match (report_type, data) {
+            (ReportType::Output, [1, leds]) | (ReportType::Output, [leds]) => {
+                self.leds = KeyboardLeds(*leds);
+            }
+            _ => (),
+        }
         Ok(())
     }
 }
@@ -0,0 +1,105 @@
+//! Where the LEDs sit relative to the keys.
+//!
+//! Positions are in sixteenths of a key, on the same grid as the 16x5 key
+//! matrix: the centre of the key at row `r`, column `c` is at
+//! `(c * 16 + 8, r * 16 + 8)`. Spatial effects use this to light up the LEDs
+//! nearest to a pressed key.
+
+/// Position units per key
+pub const KEY_UNIT: i16 = 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Position {
+    pub x: i16,
+    pub y: i16,
+}
+
+impl Position {
+    pub const fn new(x: i16, y: i16) -> Self {
+        Self { x, y }
+    }
+
+    /// Centre of the key at a matrix position
+    pub const fn of_key(row: usize, col: usize) -> Self {
+        Self {
+            x: col as i16 * KEY_UNIT + KEY_UNIT / 2,
+            y: row as i16 * KEY_UNIT + KEY_UNIT / 2,
+        }
+    }
+
+    fn distance_sq(&self, other: Position) -> u32 {
+        let dx = (self.x - other.x) as i32;
+        let dy = (self.y - other.y) as i32;
+        (dx * dx + dy * dy) as u32
+    }
+
+    /// Straight line distance, rounded down
+    pub fn distance(&self, other: Position) -> u16 {
+        isqrt(self.distance_sq(other)) as u16
+    }
+}
+
+// No FPU, and no f32::sqrt without std
+fn isqrt(n: u32) -> u32 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    return x;
+}
+
+/// The 17 LEDs run left to right along the top edge, just above the number
+/// row, evenly spread from the left of Escape to the right of Delete.
+#[rustfmt::skip]
+pub const LED_POSITIONS: [Position; 17] = [
+    Position::new(0, -4),   Position::new(16, -4),  Position::new(32, -4),
+    Position::new(48, -4),  Position::new(64, -4),  Position::new(80, -4),
+    Position::new(96, -4),  Position::new(112, -4), Position::new(128, -4),
+    Position::new(144, -4), Position::new(160, -4), Position::new(176, -4),
+    Position::new(192, -4), Position::new(208, -4), Position::new(224, -4),
+    Position::new(240, -4), Position::new(256, -4),
+];
+
+pub struct LedMap<const N: usize> {
+    positions: [Position; N],
+}
+
+impl<const N: usize> LedMap<N> {
+    pub const fn new(positions: [Position; N]) -> Self {
+        Self { positions }
+    }
+
+    /// Distance from `pos` to each LED
+    pub fn distances(&self, pos: Position) -> [u16; N] {
+        let mut ret = [0; N];
+        for (dist, led) in ret.iter_mut().zip(self.positions.iter()) {
+            *dist = led.distance(pos);
+        }
+        return ret;
+    }
+
+    /// The `K` LEDs closest to `pos`, nearest first. `K` must not be more
+    /// than the number of LEDs.
+    pub fn nearest_leds<const K: usize>(&self, pos: Position) -> [usize; K] {
+        let mut ret = [0; K];
+        for k in 0..K {
+            let mut best = None;
+            for (i, led) in self.positions.iter().enumerate() {
+                if ret[..k].contains(&i) {
+                    continue;
+                }
+                let dist = led.distance_sq(pos);
+                if best.map_or(true, |(_, d)| dist < d) {
+                    best = Some((i, dist));
+                }
+            }
+            ret[k] = best.map_or(0, |(i, _)| i);
+        }
+        return ret;
+    }
+}
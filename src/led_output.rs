@@ -0,0 +1,144 @@
+//! Output stage for the LED strip.
+//!
+//! Effects work in plain 0-255 RGB. Before a frame goes to the strip it is
+//! scaled by the global brightness, gamma corrected so that steps look even,
+//! and scaled per channel to balance the white point of the LEDs. Finally the
+//! whole frame is dimmed if it would draw more current than allowed.
+
+use smart_leds::RGB8;
+
+/// Change per press of the brightness keys
+pub const BRIGHTNESS_STEP: u8 = 16;
+pub const MIN_BRIGHTNESS: u8 = 16;
+pub const MAX_BRIGHTNESS: u8 = 255;
+
+/// Current the LEDs may draw once USB is configured, leaving the rest of the
+/// 500mA for the Pico and OLED
+pub const DEFAULT_CURRENT_BUDGET_MA: u16 = 400;
+pub const MAX_CURRENT_BUDGET_MA: u16 = 2000;
+
+// WS2812 draw at full duty, per channel, and with all channels off
+const CHANNEL_MA: u32 = 20;
+const IDLE_MA: u32 = 1;
+
+// Gamma 2.8, which suits WS2812s
+#[rustfmt::skip]
+const GAMMA: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2,
+    2, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5,
+    5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10,
+    10, 10, 11, 11, 11, 12, 12, 13, 13, 13, 14, 14, 15, 15, 16, 16,
+    17, 17, 18, 18, 19, 19, 20, 20, 21, 21, 22, 22, 23, 24, 24, 25,
+    25, 26, 27, 27, 28, 29, 29, 30, 31, 32, 32, 33, 34, 35, 35, 36,
+    37, 38, 39, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 50,
+    51, 52, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 66, 67, 68,
+    69, 70, 72, 73, 74, 75, 77, 78, 79, 81, 82, 83, 85, 86, 87, 89,
+    90, 92, 93, 95, 96, 98, 99, 101, 102, 104, 105, 107, 109, 110, 112, 114,
+    115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137, 138, 140, 142,
+    144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213,
+    215, 218, 220, 223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];
+
+/// Per-channel scale, out of 255, to correct the white balance
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Calibration {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self { r: 255, g: 255, b: 255 }
+    }
+}
+
+/// Expected current draw of `frame`, as sent to the strip
+pub fn estimate_current_ma(frame: &[RGB8]) -> u32 {
+    let duty: u32 = frame.iter().map(|led| led.r as u32 + led.g as u32 + led.b as u32).sum();
+    frame.len() as u32 * IDLE_MA + duty * CHANNEL_MA / 255
+}
+
+pub struct LedOutput {
+    brightness: u8,
+    calibration: Calibration,
+    current_limit_ma: u16,
+}
+
+impl LedOutput {
+    pub fn new(brightness: u8, calibration: Calibration) -> Self {
+        Self {
+            brightness: brightness.max(MIN_BRIGHTNESS),
+            calibration,
+            current_limit_ma: DEFAULT_CURRENT_BUDGET_MA,
+        }
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// Set the brightness, out of 255. Kept above `MIN_BRIGHTNESS` so the LEDs
+    /// can't be turned off by accident.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness.max(MIN_BRIGHTNESS);
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn current_limit_ma(&self) -> u16 {
+        self.current_limit_ma
+    }
+
+    /// Frames are scaled down to draw at most this much. Below the idle draw
+    /// of the strip the LEDs are turned off entirely.
+    pub fn set_current_limit_ma(&mut self, limit_ma: u16) {
+        self.current_limit_ma = limit_ma;
+    }
+
+    fn limit_current(&self, frame: &mut [RGB8]) {
+        let idle_ma = frame.len() as u32 * IDLE_MA;
+        let total_ma = estimate_current_ma(frame);
+        let limit_ma = self.current_limit_ma as u32;
+        if total_ma <= limit_ma {
+            return;
+        }
+
+        // Only the part above idle can be scaled
+        let available_ma = limit_ma.saturating_sub(idle_ma);
+        let active_ma = total_ma - idle_ma;
+        if active_ma == 0 {
+            return;
+        }
+        for led in frame.iter_mut() {
+            led.r = (led.r as u32 * available_ma / active_ma) as u8;
+            led.g = (led.g as u32 * available_ma / active_ma) as u8;
+            led.b = (led.b as u32 * available_ma / active_ma) as u8;
+        }
+    }
+
+    fn channel(&self, value: u8, scale: u8) -> u8 {
+        // Brightness before gamma, so brightness steps look even too
+        let value = (value as u16 * self.brightness as u16 / 255) as u8;
+        (GAMMA[value as usize] as u16 * scale as u16 / 255) as u8
+    }
+
+    /// Turn a frame of effect colors into what should be sent to the strip
+    pub fn apply(&self, frame: &mut [RGB8]) {
+        for led in frame.iter_mut() {
+            led.r = self.channel(led.r, self.calibration.r);
+            led.g = self.channel(led.g, self.calibration.g);
+            led.b = self.channel(led.b, self.calibration.b);
+        }
+        self.limit_current(frame);
+    }
+}
@@ -1,259 +1,350 @@
+use crate::compositor::{Compositor, Indicator, Slot, Styles};
+use crate::effects::{self, Effects, Params};
+use crate::keyboard::KeyboardLeds;
+use crate::led_map::{LedMap, Position};
+use crate::led_output::{Calibration, LedOutput, MAX_BRIGHTNESS};
 use rand_core::RngCore;
 use smart_leds::RGB8;
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub enum LedMode {
-    Rainbow,
-    Lightning,
-    Chase,
-    Chase2,
+/// Number of layers that can have a color overlay
+pub const MAX_LAYERS: usize = 4;
+pub const DEFAULT_TRANSITION_MS: u16 = 500;
+pub const MAX_TRANSITION_MS: u16 = 10_000;
+/// Time without an update from the host before the effects take back over
+pub const HOST_TIMEOUT_US: u64 = 2_000_000;
+
+/// Crossfade from the previous effect to the current one
+struct Transition {
+    /// `None` to fade from the frame left in `fade_leds`, black at boot
+    from: Option<usize>,
+    /// Set on the first tick after the effect changes
+    start_us: Option<u64>,
+}
+
+/// Color overlaid on the LEDs while each layer is active, `None` for no overlay
+pub type LayerColors = [Option<RGB8>; MAX_LAYERS];
+
+pub fn default_layer_colors() -> LayerColors {
+    [
+        None,
+        Some(RGB8 { r: 0, g: 80, b: 255 }),
+        Some(RGB8 { r: 255, g: 100, b: 0 }),
+        None,
+    ]
 }
 
 pub struct LedState<R: RngCore, const NUM_LEDS: usize> {
     leds: [RGB8; NUM_LEDS],
-    wheel_positions: [u8; NUM_LEDS],
-    tick_count: u32,
-    led_mode: LedMode,
-    chase_count: usize,
+    reactive_leds: [RGB8; NUM_LEDS],
+    fade_leds: [RGB8; NUM_LEDS],
+    effects: Effects<NUM_LEDS>,
+    effect: usize,
+    transition: Option<Transition>,
+    transition_us: u64,
+    // Nothing is on the strip until the first tick
+    rendered: bool,
+    now_us: u64,
+    /// Colors streamed by the host, shown instead of the effects
+    host_leds: [RGB8; NUM_LEDS],
+    /// When the host last sent colors, `None` while the firmware is in control
+    host_seen_us: Option<u64>,
+    /// Effect shown over the base one, for keypresses
+    reactive: Option<usize>,
+    layer: usize,
+    layer_colors: LayerColors,
+    locks: KeyboardLeds,
+    compositor: Compositor,
+    output: LedOutput,
+    map: LedMap<NUM_LEDS>,
     rng: R,
 }
 
 impl<R: RngCore, const NUM_LEDS: usize> LedState<R, NUM_LEDS> {
-    pub fn new(rng: R) -> Self {
+    pub fn new(rng: R, map: LedMap<NUM_LEDS>, indicators: &'static [Indicator]) -> Self {
         let mut ret = Self {
             leds: [RGB8 { r: 0, g: 0, b: 0 }; NUM_LEDS],
-            wheel_positions: [0; NUM_LEDS],
-            tick_count: 0,
-            led_mode: LedMode::Rainbow,
-            chase_count: 0,
+            reactive_leds: [RGB8 { r: 0, g: 0, b: 0 }; NUM_LEDS],
+            fade_leds: [RGB8 { r: 0, g: 0, b: 0 }; NUM_LEDS],
+            effects: Effects::new(),
+            effect: effects::RAINBOW,
+            transition: None,
+            transition_us: DEFAULT_TRANSITION_MS as u64 * 1000,
+            rendered: false,
+            now_us: 0,
+            host_leds: [RGB8 { r: 0, g: 0, b: 0 }; NUM_LEDS],
+            host_seen_us: None,
+            reactive: None,
+            layer: 0,
+            layer_colors: default_layer_colors(),
+            locks: KeyboardLeds::default(),
+            compositor: Compositor::new(indicators),
+            output: LedOutput::new(MAX_BRIGHTNESS, Calibration::default()),
+            map,
             rng,
         };
 
-        ret.set_mode(LedMode::Chase2);
+        ret.set_effect(effects::CHASE_2);
         return ret;
     }
 
-    pub fn clear(&mut self) {
-        for led in self.leds.iter_mut() {
-            led.r = 0;
-            led.g = 0;
-            led.b = 0;
+    /// Select the effect at `index` in the registry, crossfading from the
+    /// current one. Out of range indexes are ignored.
+    ///
+    /// Before the first tick the strip is still dark, so the effect selected
+    /// at boot fades in from black.
+    pub fn set_effect(&mut self, index: usize) {
+        if index >= effects::COUNT {
+            return;
+        }
+        if index != self.effect || !self.rendered {
+            self.transition = Some(Transition {
+                from: Some(self.effect).filter(|_| self.rendered),
+                start_us: None,
+            });
         }
+        self.effect = index;
+        self.effects.get_mut(index).init();
     }
 
-    pub fn set_mode(&mut self, mode: LedMode) {
-        match mode {
-            LedMode::Rainbow => {
-                self.init_rainbow();
-            }
-            LedMode::Lightning => {
-                self.init_lightning();
-            }
-            LedMode::Chase => {
-                self.init_chase();
-            }
-            LedMode::Chase2 => {
-                self.init_chase_2();
-            }
-        }
+    pub fn transition_ms(&self) -> u16 {
+        (self.transition_us / 1000) as u16
     }
 
-    fn init_rainbow(&mut self) {
-        self.led_mode = LedMode::Rainbow;
+    /// How long switching effects takes, 0 to switch straight away
+    pub fn set_transition_ms(&mut self, ms: u16) {
+        self.transition_us = ms.min(MAX_TRANSITION_MS) as u64 * 1000;
+    }
 
-        let step = u8::MAX as f32 / NUM_LEDS as f32;
+    pub fn effect(&self) -> usize {
+        self.effect
+    }
 
-        for (i, wheel_pos) in self.wheel_positions.iter_mut().enumerate() {
-            *wheel_pos = (i as f32 * step) as u8;
+    /// Select the reactive effect layered over the base one, or turn it off.
+    /// Out of range indexes are ignored.
+    pub fn set_reactive(&mut self, index: Option<usize>) {
+        match index {
+            Some(i) if i >= effects::COUNT => return,
+            Some(i) => self.effects.get_mut(i).init(),
+            None => (),
         }
+        self.reactive = index;
     }
 
-    fn init_lightning(&mut self) {
-        self.led_mode = LedMode::Lightning;
-        self.clear();
+    pub fn reactive(&self) -> Option<usize> {
+        self.reactive
     }
 
-    fn init_chase(&mut self) {
-        self.led_mode = LedMode::Chase;
-        self.clear();
+    /// Whether the effect at `index` is on the strip, as the base or reactive
+    /// effect or fading out
+    pub fn is_showing(&self, index: usize) -> bool {
+        self.effect == index
+            || self.reactive_layer() == Some(index)
+            || self.transition.as_ref().map_or(false, |t| t.from == Some(index))
     }
 
-    fn init_chase_2(&mut self) {
-        self.led_mode = LedMode::Chase2;
-        self.clear();
+    pub fn effect_id_at(&self, index: usize) -> &'static str {
+        self.effects.get(index).id()
     }
 
-    fn tick_rainbow(&mut self) {
-        if self.tick_count < 10 {
-            self.tick_count += 1;
-            return;
-        } else {
-            self.tick_count = 0;
+    // The reactive effect, unless it's the base one too
+    fn reactive_layer(&self) -> Option<usize> {
+        self.reactive.filter(|r| *r != self.effect)
+    }
 
-            for i in 0..NUM_LEDS {
-                self.leds[i] = Self::wheel_rgb(self.wheel_positions[i]);
-                self.wheel_positions[i] = self.wheel_positions[i].wrapping_add(1);
-            }
-        }
+    pub fn effect_name(&self) -> &'static str {
+        self.effects.get(self.effect).name()
     }
 
-    fn tick_lightning(&mut self) {
-        if self.tick_count < 10 {
-            self.tick_count += 1;
-            return;
-        } else {
-            self.tick_count = 0;
-
-            if self.one_in_chance(20) {
-                let random_key_index = self.rand_index(NUM_LEDS);
-
-                if self.leds[random_key_index].r < 100 {
-                    if self.one_in_chance(2) {
-                        self.leds[random_key_index].r = 200;
-                        self.leds[random_key_index].g = 200;
-                        self.leds[random_key_index].b = 0;
-                    } else {
-                        self.leds[random_key_index].r = 200;
-                        self.leds[random_key_index].g = 200;
-                        self.leds[random_key_index].b = 70;
-                    }
-                }
-            }
+    pub fn effect_id(&self) -> &'static str {
+        self.effects.get(self.effect).id()
+    }
 
-            for led in self.leds.iter_mut() {
-                led.r = led.r.saturating_sub(2);
-                led.g = led.g.saturating_sub(2);
-                led.b = led.b.saturating_sub(2);
-            }
-        }
+    /// Speed and color of the current effect, if it has them
+    pub fn params(&self) -> Option<Params> {
+        self.effects.params(self.effect)
     }
 
-    fn tick_chase(&mut self) {
-        if self.tick_count < 10 {
-            self.tick_count += 1;
-            return;
-        } else {
-            self.tick_count = 0;
-            for led in self.leds.iter_mut() {
-                led.r = led.r.saturating_sub(1);
-                led.g = led.g.saturating_sub(1);
-                led.b = led.b.saturating_sub(1);
+    pub fn set_params(&mut self, params: Params) {
+        self.effects.set_params(self.effect, params);
+    }
+
+    /// Params of every effect, e.g. from settings. `None` leaves an effect
+    /// as it is.
+    pub fn load_params(&mut self, params: &[Option<Params>; effects::COUNT]) {
+        for (index, params) in params.iter().enumerate() {
+            if let Some(params) = params {
+                self.effects.set_params(index, *params);
             }
         }
     }
 
-    fn tick_chase_2(&mut self) {
-        if self.tick_count < 100 {
-            self.tick_count += 1;
-            return;
-        } else {
-            self.tick_count = 0;
+    /// Index of the effect with `id`, or `id` as an index
+    pub fn find_effect(&self, id: &str) -> Option<usize> {
+        self.effects
+            .find(id)
+            .or_else(|| id.parse().ok().filter(|i| *i < effects::COUNT))
+    }
 
-            self.chase_count += 1;
-            if self.chase_count >= NUM_LEDS {
-                self.chase_count = 0;
-            }
+    /// Where the LEDs are, relative to the keys
+    pub fn map(&self) -> &LedMap<NUM_LEDS> {
+        &self.map
+    }
 
-            let chase_2 = (self.chase_count + NUM_LEDS / 2) % NUM_LEDS;
+    /// Per-LED key usage, 0-255, shown by the heatmap effect.
+    pub fn set_heat_levels(&mut self, levels: [u8; NUM_LEDS]) {
+        self.effects.heatmap.set_levels(levels);
+    }
 
-            self.wheel_positions[0] = self.wheel_positions[0].wrapping_add(10);
-            self.leds[self.chase_count] = Self::wheel_rgb(self.wheel_positions[0]);
-            self.leds[chase_2] = Self::wheel_rgb(self.wheel_positions[0]);
+    /// The active layer, from `Layout::current_layer()`
+    pub fn set_layer(&mut self, layer: usize) {
+        self.layer = layer;
+    }
 
-            for led in self.leds.iter_mut() {
-                led.r = led.r.saturating_sub(20);
-                led.g = led.g.saturating_sub(20);
-                led.b = led.b.saturating_sub(20);
-            }
-        }
+    pub fn set_layer_colors(&mut self, colors: LayerColors) {
+        self.layer_colors = colors;
     }
 
-    fn handle_keypress_lightning(&mut self) {
-        let random_key_index = self.rand_index(NUM_LEDS);
+    /// Lock states from the host, for the indicator LEDs
+    pub fn set_locks(&mut self, locks: KeyboardLeds) {
+        self.locks = locks;
+    }
 
-        if self.one_in_chance(2) {
-            self.leds[random_key_index].r = 255;
-            self.leds[random_key_index].g = 255;
-            self.leds[random_key_index].b = 0;
-        } else {
-            self.leds[random_key_index].r = 255;
-            self.leds[random_key_index].g = 255;
-            self.leds[random_key_index].b = 125;
-        }
+    /// Blend mode and opacity of each level of the stack
+    pub fn styles(&self) -> Styles {
+        self.compositor.styles()
     }
 
-    fn handle_keypress_chase(&mut self) {
-        self.chase_count += 1;
-        if self.chase_count >= NUM_LEDS {
-            self.chase_count = 0;
-        }
+    pub fn set_styles(&mut self, styles: Styles) {
+        self.compositor.set_styles(styles);
+    }
 
-        self.wheel_positions[0] = self.wheel_positions[0].wrapping_add(10);
-        self.leds[self.chase_count] = Self::wheel_rgb(self.wheel_positions[0])
+    /// Brightness and color calibration
+    pub fn output(&self) -> &LedOutput {
+        &self.output
     }
 
-    pub fn handle_keypress(&mut self) {
-        match self.led_mode {
-            LedMode::Lightning => {
-                self.handle_keypress_lightning();
-            }
-            LedMode::Chase => {
-                self.handle_keypress_chase();
-            }
-            _ => (),
+    pub fn output_mut(&mut self) -> &mut LedOutput {
+        &mut self.output
+    }
+
+    pub fn is_host_controlled(&self) -> bool {
+        self.host_seen_us.is_some()
+    }
+
+    // The rest of the strip starts off black when the host takes over
+    fn take_host_control(&mut self) {
+        if self.host_seen_us.is_none() {
+            self.host_leds = [RGB8 { r: 0, g: 0, b: 0 }; NUM_LEDS];
         }
+        self.host_seen_us = Some(self.now_us);
     }
 
-    fn one_in_chance(&mut self, chance: u32) -> bool {
-        if self.rand_index(chance as usize) == 0 {
-            true
-        } else {
-            false
+    /// Set one LED from the host. Returns false if `index` is out of range.
+    pub fn set_host_led(&mut self, index: usize, color: RGB8) -> bool {
+        if index >= NUM_LEDS {
+            return false;
         }
+        self.take_host_control();
+        self.host_leds[index] = color;
+        return true;
     }
 
-    fn rand_index(&mut self, len: usize) -> usize {
-        let random_num = self.rng.next_u32();
-        let ret = random_num as usize % len;
-        return ret;
+    /// Set LEDs from the host, starting at the first one
+    pub fn set_host_frame(&mut self, colors: &[RGB8]) {
+        self.take_host_control();
+        for (led, color) in self.host_leds.iter_mut().zip(colors.iter()) {
+            *led = *color;
+        }
     }
 
-    pub fn tick(&mut self) {
-        // TODO: Add modes
-        match self.led_mode {
-            LedMode::Rainbow => self.tick_rainbow(),
-            LedMode::Lightning => self.tick_lightning(),
-            LedMode::Chase => self.tick_chase(),
-            LedMode::Chase2 => self.tick_chase_2(),
+    /// Hand the LEDs back to the effects, crossfading from the host's colors
+    pub fn release_host(&mut self) {
+        if self.host_seen_us.take().is_some() {
+            self.fade_leds = self.host_leds;
+            self.transition = Some(Transition {
+                from: None,
+                start_us: None,
+            });
         }
     }
 
-    fn wheel_rgb(mut wheel_pos: u8) -> RGB8 {
-        wheel_pos = 255 - wheel_pos;
+    /// `key` is the pressed key's position, `None` if it isn't known
+    pub fn handle_keypress(&mut self, key: Option<Position>) {
+        self.effects
+            .handle_keypress(self.effect, key, &self.map, &mut self.rng);
+        if let Some(reactive) = self.reactive_layer() {
+            self.effects
+                .handle_keypress(reactive, key, &self.map, &mut self.rng);
+        }
+    }
 
-        let mut rgb = RGB8 { r: 0, g: 0, b: 0 };
+    /// `now_us` is from `PicoClock`
+    pub fn tick(&mut self, now_us: u64) {
+        self.now_us = now_us;
+        if let Some(seen_us) = self.host_seen_us {
+            if now_us.saturating_sub(seen_us) >= HOST_TIMEOUT_US {
+                self.release_host();
+            }
+        }
 
-        if wheel_pos < 85 {
-            rgb.r = 255 - wheel_pos * 3;
-            rgb.g = 0;
-            rgb.b = wheel_pos * 3;
-        } else if wheel_pos < 170 {
-            wheel_pos -= 85;
-            rgb.r = 0;
-            rgb.g = wheel_pos * 3;
-            rgb.b = 255 - wheel_pos * 3;
-        } else {
-            wheel_pos -= 170;
-            rgb.r = wheel_pos * 3;
-            rgb.g = 255 - wheel_pos * 3;
-            rgb.b = 0;
+        self.effects.tick(self.effect, now_us, &mut self.rng);
+        self.effects.render(self.effect, &mut self.leds);
+        self.rendered = true;
+
+        let reactive = self.reactive_layer();
+        if let Some(transition) = self.transition.as_mut() {
+            let start_us = *transition.start_us.get_or_insert(now_us);
+            let elapsed_us = now_us - start_us;
+
+            if elapsed_us >= self.transition_us {
+                self.transition = None;
+            } else {
+                // The outgoing effect keeps running while it fades out, unless
+                // it's the reactive effect, which is ticked below anyway
+                if let Some(from) = transition.from {
+                    if Some(from) != reactive {
+                        self.effects.tick(from, now_us, &mut self.rng);
+                    }
+                    self.effects.render(from, &mut self.fade_leds);
+                }
+
+                let amount = (elapsed_us * 255 / self.transition_us) as u16;
+                for (led, from) in self.leds.iter_mut().zip(self.fade_leds.iter()) {
+                    led.r = Self::mix(from.r, led.r, amount);
+                    led.g = Self::mix(from.g, led.g, amount);
+                    led.b = Self::mix(from.b, led.b, amount);
+                }
+            }
         }
 
-        return rgb;
+        if let Some(reactive) = reactive {
+            self.effects.tick(reactive, now_us, &mut self.rng);
+            self.effects.render(reactive, &mut self.reactive_leds);
+        }
+    }
+
+    fn mix(a: u8, b: u8, amount: u16) -> u8 {
+        ((a as u16 * (255 - amount) + b as u16 * amount) / 255) as u8
     }
 
     pub fn get_grb(&self) -> [RGB8; NUM_LEDS] {
-        let mut ret = self.leds.clone();
+        let mut ret = [RGB8 { r: 0, g: 0, b: 0 }; NUM_LEDS];
+
+        // Host colors are still dimmed and current limited below
+        if self.is_host_controlled() {
+            ret = self.host_leds;
+        } else {
+            self.compositor.blend(Slot::Base, &mut ret, &self.leds);
+            if self.reactive_layer().is_some() {
+                self.compositor.blend(Slot::Reactive, &mut ret, &self.reactive_leds);
+            }
+            if let Some(Some(color)) = self.layer_colors.get(self.layer) {
+                self.compositor.blend_color(Slot::Tint, &mut ret, *color);
+            }
+            self.compositor.blend_indicators(&mut ret, self.locks);
+        }
+
+        self.output.apply(&mut ret);
 
         for grb in ret.iter_mut() {
             let temp_r = grb.r;
@@ -3,32 +3,61 @@
 
 use panic_halt as _;
 
+mod animation;
+mod blanking;
+mod debounce;
+mod diagnostics;
 mod display;
+mod effects;
+mod flash;
+mod framebuffer;
+mod heatmap;
+mod host;
+mod images;
 mod keyboard;
+mod led_map;
+mod led_output;
 mod led_state;
+mod notify;
+mod pages;
+mod screen;
+mod settings;
+mod sleep;
 mod slow_matrix;
 mod ws2812_pio;
 mod clock;
+mod compositor;
+mod wpm;
 
-#[rtic::app(device = rp_pico::hal::pac, peripherals = true)]
+#[rtic::app(device = rp_pico::hal::pac, peripherals = true, dispatchers = [SPI0_IRQ])]
 mod app {
-    use crate::display::CaeDisplay;
+    use crate::debounce::KeyDebouncer;
+    use crate::diagnostics::Diagnostics;
+    use crate::display::{CaeDisplay, OledConfig};
+    use crate::heatmap::Heatmap;
+    use crate::host::{HostChannel, HostCommand};
+    use crate::images;
     use crate::keyboard::{KbHidReport, MediaKey, MediaKeyHidReport, MediaKeyboard};
-    use crate::led_state::{LedMode, LedState};
+    use crate::compositor::{Indicator, Lock};
+    use crate::effects::{self, Param};
+    use crate::led_map::{self, LedMap, Position};
+    use crate::led_output::{self, BRIGHTNESS_STEP};
+    use crate::led_state::LedState;
+    use crate::settings::SettingsStore;
+    use crate::sleep::IdleSleep;
     use crate::slow_matrix::SlowMatrix;
     use crate::ws2812_pio::Ws2812Direct;
-    use crate::clock::PicoClock;
+    use crate::clock::{PicoClock, WallClock};
     use cortex_m::prelude::_embedded_hal_watchdog_Watchdog;
+    use cortex_m::prelude::_embedded_hal_watchdog_WatchdogDisable;
     use cortex_m::prelude::_embedded_hal_watchdog_WatchdogEnable;
     use embedded_time::duration::units::*;
     use embedded_time::rate::Extensions;
     use keyberon::action::Action;
-    use keyberon::debounce::Debouncer;
     use keyberon::hid;
     use keyberon::key_code;
     use keyberon::layout::CustomEvent;
     use keyberon::layout::Layout;
-    use keyberon::matrix::PressedKeys;
     use rp_pico::hal::gpio::DynPin;
     use rp_pico::hal::usb::UsbBus;
     use rp_pico::pac::{I2C0, PIO0};
@@ -48,36 +77,115 @@ mod app {
         },
         XOSC_CRYSTAL_FREQ,
     };
-    use smart_leds::SmartLedsWrite;
+    use smart_leds::{SmartLedsWrite, RGB8};
     use usb_device::class_prelude::*;
+    use usb_device::device::{UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
     use embedded_time::clock::Clock as EmbClock;
+    use core::fmt::Write;
 
     const SCAN_TIME_US: u32 = 1000;
     const NUM_LEDS: usize = 17;
     const NUM_COLUMNS: usize = 16;
     const NUM_ROWS: usize = 5;
+    const WATCHDOG_TIMEOUT_US: u32 = 1_000_000;
+    // Time without key activity before the keyboard goes to sleep
+    const IDLE_SLEEP_TIMEOUT_MS: u32 = 10 * 60 * 1000;
+    // GPIO numbers of COL0..COL15, used to arm the wake interrupts
+    const COLUMN_GPIOS: [u8; NUM_COLUMNS] = [0, 1, 2, 3, 6, 7, 8, 9, 10, 11, 12, 14, 15, 16, 17, 18];
+    // Number of switches listed in diagnostics reports
+    const NUM_DIAG_OFFENDERS: usize = 5;
+
+    // OLED panel, picked with the `display-128x32` and `display-flipped`
+    // features
+    #[cfg(not(feature = "display-128x32"))]
+    type OledSize = ssd1306::size::DisplaySize128x64;
+    #[cfg(feature = "display-128x32")]
+    type OledSize = ssd1306::size::DisplaySize128x32;
+    #[cfg(not(feature = "display-flipped"))]
+    const OLED_ROTATION: ssd1306::rotation::DisplayRotation = ssd1306::rotation::DisplayRotation::Rotate0;
+    #[cfg(feature = "display-flipped")]
+    const OLED_ROTATION: ssd1306::rotation::DisplayRotation = ssd1306::rotation::DisplayRotation::Rotate180;
+    const OLED_ADDRESS: u8 = 0x3C;
+    // The LED above Escape, nearest Caps Lock, turns red while it's on
+    const LED_INDICATORS: [Indicator; 1] = [Indicator {
+        lock: Lock::Caps,
+        led: 0,
+        color: RGB8 { r: 255, g: 0, b: 0 },
+    }];
+    // Until the host configures us we may only draw 100mA in total, and next
+    // to nothing while suspended
+    const UNCONFIGURED_LED_BUDGET_MA: u16 = 50;
+    const SUSPENDED_LED_BUDGET_MA: u16 = 0;
 
     static mut USB_BUS: Option<usb_device::bus::UsbBusAllocator<rp_pico::hal::usb::UsbBus>> = None;
 
     #[derive(Debug, Clone, Copy, Eq, PartialEq)]
     pub enum CustomActions {
-        SetModeRainbow,
-        SetModeLightning,
-        SetModeChase,
-        SetModeChase2,
+        /// Select an effect by its index in `effects::Effects`
+        SetEffect(usize),
         RestartToUf2,
+        DebounceThresholdUp,
+        DebounceThresholdDown,
+        CycleDebounceAlgorithm,
+        ShowDiagnostics,
+        NextPage,
+        BrightnessUp,
+        BrightnessDown,
+        NextEffect,
+        /// Step a parameter of the current effect
+        ParamUp(Param),
+        ParamDown(Param),
     }
 
-    const ACTION_SET_MODE_RAINBOW: Action<CustomActions> =
-        Action::Custom(CustomActions::SetModeRainbow);
-    const ACTION_SET_MODE_LIGHTNING: Action<CustomActions> =
-        Action::Custom(CustomActions::SetModeLightning);
-    const ACTION_SET_MODE_CHASE: Action<CustomActions> =
-        Action::Custom(CustomActions::SetModeChase);
-    const ACTION_SET_MODE_CHASE_2: Action<CustomActions> =
-        Action::Custom(CustomActions::SetModeChase2);
+    const ACTION_EFFECT_RAINBOW: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::RAINBOW));
+    const ACTION_EFFECT_LIGHTNING: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::LIGHTNING));
+    const ACTION_EFFECT_CHASE: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::CHASE));
+    const ACTION_EFFECT_CHASE_2: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::CHASE_2));
+    const ACTION_EFFECT_HEATMAP: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::HEATMAP));
+    const ACTION_EFFECT_RIPPLE: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::RIPPLE));
+    const ACTION_EFFECT_SPLASH: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::SPLASH));
+    const ACTION_EFFECT_GLOW: Action<CustomActions> =
+        Action::Custom(CustomActions::SetEffect(effects::GLOW));
+    const ACTION_NEXT_EFFECT: Action<CustomActions> = Action::Custom(CustomActions::NextEffect);
+    const ACTION_HUE_UP: Action<CustomActions> = Action::Custom(CustomActions::ParamUp(Param::Hue));
+    const ACTION_HUE_DOWN: Action<CustomActions> =
+        Action::Custom(CustomActions::ParamDown(Param::Hue));
+    const ACTION_SAT_UP: Action<CustomActions> =
+        Action::Custom(CustomActions::ParamUp(Param::Saturation));
+    const ACTION_SAT_DOWN: Action<CustomActions> =
+        Action::Custom(CustomActions::ParamDown(Param::Saturation));
+    const ACTION_VAL_UP: Action<CustomActions> = Action::Custom(CustomActions::ParamUp(Param::Value));
+    const ACTION_VAL_DOWN: Action<CustomActions> =
+        Action::Custom(CustomActions::ParamDown(Param::Value));
+    const ACTION_SPEED_UP: Action<CustomActions> =
+        Action::Custom(CustomActions::ParamUp(Param::Speed));
+    const ACTION_SPEED_DOWN: Action<CustomActions> =
+        Action::Custom(CustomActions::ParamDown(Param::Speed));
     const ACTION_RESTART_TO_UF2: Action<CustomActions> =
         Action::Custom(CustomActions::RestartToUf2);
+    const ACTION_DEBOUNCE_UP: Action<CustomActions> =
+        Action::Custom(CustomActions::DebounceThresholdUp);
+    const ACTION_DEBOUNCE_DOWN: Action<CustomActions> =
+        Action::Custom(CustomActions::DebounceThresholdDown);
+    const ACTION_DEBOUNCE_ALGORITHM: Action<CustomActions> =
+        Action::Custom(CustomActions::CycleDebounceAlgorithm);
+    const ACTION_SHOW_DIAGNOSTICS: Action<CustomActions> =
+        Action::Custom(CustomActions::ShowDiagnostics);
+    const ACTION_NEXT_PAGE: Action<CustomActions> = Action::Custom(CustomActions::NextPage);
+    const ACTION_BRIGHTNESS_UP: Action<CustomActions> =
+        Action::Custom(CustomActions::BrightnessUp);
+    const ACTION_BRIGHTNESS_DOWN: Action<CustomActions> =
+        Action::Custom(CustomActions::BrightnessDown);
+
+    // Names shown on the OLED for each layer below
+    const LAYER_NAMES: [&str; 3] = ["Base", "LED/Media", "Function"];
 
     #[rustfmt::skip]
     pub static LAYERS: keyberon::layout::Layers<CustomActions> = keyberon::layout::layout! {
@@ -90,9 +198,9 @@ mod app {
 
         }
         {
-            [t {ACTION_SET_MODE_RAINBOW} {ACTION_SET_MODE_LIGHTNING} {ACTION_SET_MODE_CHASE} {ACTION_SET_MODE_CHASE_2} t t t t t t t t t t {ACTION_RESTART_TO_UF2} ]
-            [t t t t t t t t t t t t t t t t ]
-            [t t t t t t t t t t t t t t t MediaVolUp ]
+            [t {ACTION_EFFECT_RAINBOW} {ACTION_EFFECT_LIGHTNING} {ACTION_EFFECT_CHASE} {ACTION_EFFECT_CHASE_2} {ACTION_EFFECT_HEATMAP} {ACTION_EFFECT_RIPPLE} {ACTION_EFFECT_SPLASH} {ACTION_EFFECT_GLOW} {ACTION_NEXT_EFFECT} t {ACTION_BRIGHTNESS_DOWN} {ACTION_BRIGHTNESS_UP} t t {ACTION_RESTART_TO_UF2} ]
+            [t t {ACTION_HUE_UP} {ACTION_SAT_UP} {ACTION_VAL_UP} {ACTION_SPEED_UP} t t t {ACTION_SHOW_DIAGNOSTICS} t {ACTION_NEXT_PAGE} {ACTION_DEBOUNCE_DOWN} {ACTION_DEBOUNCE_UP} {ACTION_DEBOUNCE_ALGORITHM} t ]
+            [t t {ACTION_HUE_DOWN} {ACTION_SAT_DOWN} {ACTION_VAL_DOWN} {ACTION_SPEED_DOWN} t t t t t t t t t MediaVolUp ]
             [t t t t t t t t t MediaPreviousSong MediaNextSong t t Up t MediaVolDown ]
             [t t t t t t MediaPlayPause t t t t Left t Down Right n ]
         }
@@ -113,6 +221,7 @@ mod app {
             rp_pico::hal::usb::UsbBus,
             crate::keyboard::MediaKeyboard,
         >,
+        host: HostChannel<rp_pico::hal::usb::UsbBus>,
         timer: hal::timer::Timer,
         alarm: hal::timer::Alarm0,
         #[lock_free]
@@ -121,13 +230,23 @@ mod app {
         matrix: SlowMatrix<DynPin, DynPin, NUM_COLUMNS, NUM_ROWS>,
         layout: Layout<CustomActions>,
         #[lock_free]
-        debouncer: Debouncer<PressedKeys<NUM_COLUMNS, NUM_ROWS>>,
+        debouncer: KeyDebouncer<NUM_COLUMNS, NUM_ROWS>,
         #[lock_free]
         led_driver: Ws2812Direct<PIO0, SM0, Gpio13>,
         #[lock_free]
         led_state: LedState<rosc::RingOscillator<rosc::Enabled>, NUM_LEDS>,
         #[lock_free]
-        display: CaeDisplay<I2C<I2C0, (Pin<Gpio4, FunctionI2C>, Pin<Gpio5, FunctionI2C>)>>
+        display: CaeDisplay<I2C<I2C0, (Pin<Gpio4, FunctionI2C>, Pin<Gpio5, FunctionI2C>)>, OledSize>,
+        #[lock_free]
+        idle_sleep: IdleSleep<NUM_COLUMNS>,
+        #[lock_free]
+        settings: SettingsStore,
+        #[lock_free]
+        diagnostics: Diagnostics<NUM_COLUMNS, NUM_ROWS>,
+        #[lock_free]
+        heatmap: Heatmap<NUM_COLUMNS, NUM_ROWS>,
+        #[lock_free]
+        wall_clock: WallClock,
     }
 
     #[local]
@@ -175,8 +294,15 @@ mod app {
             clocks.peripheral_clock.freq(),
         );
 
-        let mut display = CaeDisplay::new(i2c);
-        display.handle_keypress();
+        let display = CaeDisplay::new(
+            i2c,
+            OledSize {},
+            OledConfig {
+                rotation: OLED_ROTATION,
+                address: OLED_ADDRESS,
+                ..OledConfig::default()
+            },
+        );
 
         let rng = rosc::RingOscillator::new(c.device.ROSC).initialize();
 
@@ -224,7 +350,18 @@ mod app {
             clocks.peripheral_clock.freq(),
         );
 
-        let led_state: LedState<rosc::RingOscillator<rosc::Enabled>, NUM_LEDS> = LedState::new(rng);
+        let settings = SettingsStore::load();
+
+        let mut led_state: LedState<rosc::RingOscillator<rosc::Enabled>, NUM_LEDS> = LedState::new(rng, LedMap::new(led_map::LED_POSITIONS), &LED_INDICATORS);
+        // Before the first frame, so the saved effect fades in at boot
+        led_state.set_transition_ms(settings.get().led_transition_ms);
+        led_state.load_params(&settings.get().effect_params);
+        led_state.set_effect(settings.get().led_effect);
+        led_state.set_layer_colors(settings.get().layer_colors);
+        led_state.output_mut().set_brightness(settings.get().brightness);
+        led_state.output_mut().set_calibration(settings.get().calibration);
+        led_state.set_reactive(settings.get().reactive_effect);
+        led_state.set_styles(settings.get().led_styles);
 
         let matrix: SlowMatrix<DynPin, DynPin, NUM_COLUMNS, NUM_ROWS> =
             cortex_m::interrupt::free(move |_cs| {
@@ -259,9 +396,8 @@ mod app {
             .unwrap();
 
         let layout = Layout::new(LAYERS);
-        let debouncer: keyberon::debounce::Debouncer<
-            keyberon::matrix::PressedKeys<NUM_COLUMNS, NUM_ROWS>,
-        > = Debouncer::new(PressedKeys::default(), PressedKeys::default(), 10);
+        let debouncer: KeyDebouncer<NUM_COLUMNS, NUM_ROWS> =
+            KeyDebouncer::new(settings.get().debounce);
 
         let mut timer = hal::Timer::new(c.device.TIMER, &mut resets);
         let y = PicoClock::new(&timer);
@@ -286,15 +422,32 @@ mod app {
         let usb_class = hid::HidClass::new(MediaKeyboard::default(), unsafe {
             USB_BUS.as_ref().unwrap()
         });
-        let usb_dev = keyberon::new_device(unsafe { USB_BUS.as_ref().unwrap() });
+        let host = HostChannel::new(unsafe { USB_BUS.as_ref().unwrap() });
+        // Same IDs as keyberon::new_device, but composite so that the CDC
+        // serial host channel can sit alongside the keyboard.
+        let usb_dev = UsbDeviceBuilder::new(
+            unsafe { USB_BUS.as_ref().unwrap() },
+            UsbVidPid(keyberon::VID, keyberon::PID),
+        )
+        .manufacturer("caekbd")
+        .product("caekbd")
+        .serial_number(env!("CARGO_PKG_VERSION"))
+        .composite_with_iads()
+        .build();
+
+        let idle_sleep = IdleSleep::new(
+            COLUMN_GPIOS,
+            IDLE_SLEEP_TIMEOUT_MS * 1000 / SCAN_TIME_US,
+        );
 
         // Start watchdog and feed it with the lowest priority task at 1000hz
-        watchdog.start(1_000_000.microseconds());
+        watchdog.start(WATCHDOG_TIMEOUT_US.microseconds());
 
         (
             Shared {
                 usb_dev,
                 usb_class,
+                host,
                 timer,
                 alarm,
                 watchdog,
@@ -304,72 +457,384 @@ mod app {
                 led_driver,
                 led_state,
                 display,
+                idle_sleep,
+                settings,
+                diagnostics: Diagnostics::new(),
+                heatmap: Heatmap::load(),
+                wall_clock: WallClock::new(),
             },
             Local {},
             init::Monotonics(),
         )
     }
 
-    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [usb_dev, usb_class])]
+    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [usb_dev, usb_class, host])]
     fn usb_rx(c: usb_rx::Context) {
         let mut usb_d = c.shared.usb_dev;
         let mut usb_c = c.shared.usb_class;
-        usb_d.lock(|d| {
-            usb_c.lock(|c| {
-                if d.poll(&mut [c]) {
-                    c.poll();
-                }
-            })
+        let mut host = c.shared.host;
+        (&mut usb_d, &mut usb_c, &mut host).lock(|d, c, h| {
+            if d.poll(&mut [c, h.serial()]) {
+                c.poll();
+                // Answered with "err busy" if the queue is full
+                h.poll_commands(|command| host_command::spawn(command).is_ok());
+            }
+            h.flush();
         });
     }
 
+    #[task(priority = 1, capacity = 4, shared = [host, timer, diagnostics, heatmap, display, wall_clock, led_state, settings])]
+    fn host_command(mut c: host_command::Context, command: HostCommand) {
+        match command {
+            HostCommand::Diagnostics => {
+                let offenders = c.shared.diagnostics.worst_offenders::<NUM_DIAG_OFFENDERS>();
+                c.shared.host.lock(|h| {
+                    if offenders.is_empty() {
+                        h.reply(format_args!("diag none"));
+                    }
+                    for offender in offenders.iter() {
+                        h.reply(format_args!("diag {}", offender));
+                    }
+                });
+            }
+            HostCommand::ResetDiagnostics => {
+                c.shared.diagnostics.reset();
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            // Written out by the scan as the host reads it
+            HostCommand::ExportHeatmap(format) => c.shared.heatmap.start_export(format),
+            HostCommand::ResetHeatmap => {
+                c.shared.heatmap.reset();
+                c.shared.heatmap.snapshot();
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Notify(notification) => {
+                c.shared.display.notify(notification);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::ClearNotification(id) => {
+                c.shared.display.clear_notification(id);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Time => {
+                let now_us = c.shared.timer.lock(|t| PicoClock::new(t).now_us());
+                match c.shared.wall_clock.local_time(now_us) {
+                    Some(t) => c.shared.host.lock(|h| {
+                        h.reply(format_args!(
+                            "time {}-{:02}-{:02} {:02}:{:02}:{:02}",
+                            t.year, t.month, t.day, t.hour, t.minute, t.second
+                        ))
+                    }),
+                    None => c.shared.host.lock(|h| h.reply(format_args!("err time not set"))),
+                }
+            }
+            HostCommand::Effect(None) => {
+                let led_state = &*c.shared.led_state;
+                c.shared.host.lock(|h| {
+                    h.reply(format_args!("effect {} {}", led_state.effect(), led_state.effect_id()))
+                });
+            }
+            HostCommand::Effect(Some(id)) => match c.shared.led_state.find_effect(&id) {
+                Some(index) => {
+                    c.shared.led_state.set_effect(index);
+                    c.shared.settings.update(|s| s.led_effect = index);
+                    c.shared.host.lock(|h| h.reply(format_args!("ok")));
+                }
+                None => c.shared.host.lock(|h| h.reply(format_args!("err unknown effect"))),
+            },
+            HostCommand::Reactive(None) => {
+                let led_state = &*c.shared.led_state;
+                match led_state.reactive() {
+                    Some(index) => c.shared.host.lock(|h| {
+                        h.reply(format_args!("reactive {} {}", index, led_state.effect_id_at(index)))
+                    }),
+                    None => c.shared.host.lock(|h| h.reply(format_args!("reactive off"))),
+                }
+            }
+            HostCommand::Reactive(Some(id)) => {
+                let index = if id.as_str() == "off" {
+                    Some(None)
+                } else {
+                    c.shared.led_state.find_effect(&id).map(Some)
+                };
+                match index {
+                    Some(index) => {
+                        c.shared.led_state.set_reactive(index);
+                        c.shared.settings.update(|s| s.reactive_effect = index);
+                        c.shared.host.lock(|h| h.reply(format_args!("ok")));
+                    }
+                    None => c.shared.host.lock(|h| h.reply(format_args!("err unknown effect"))),
+                }
+            }
+            HostCommand::Blend { slot, style: None } => {
+                let style = c.shared.led_state.styles()[slot as usize];
+                c.shared.host.lock(|h| {
+                    h.reply(format_args!("blend {} {} {}", slot.id(), style.blend.id(), style.opacity))
+                });
+            }
+            HostCommand::Blend {
+                slot,
+                style: Some(style),
+            } => {
+                let mut styles = c.shared.led_state.styles();
+                styles[slot as usize] = style;
+                c.shared.led_state.set_styles(styles);
+                c.shared.settings.update(|s| s.led_styles = styles);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::LayerColor { layer, color } => {
+                c.shared.settings.update(|s| s.layer_colors[layer] = color);
+                c.shared.led_state.set_layer_colors(c.shared.settings.get().layer_colors);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Brightness(None) => {
+                let brightness = c.shared.led_state.output().brightness();
+                c.shared.host.lock(|h| h.reply(format_args!("brightness {}", brightness)));
+            }
+            HostCommand::Brightness(Some(brightness)) => {
+                c.shared.led_state.output_mut().set_brightness(brightness);
+                let brightness = c.shared.led_state.output().brightness();
+                c.shared.settings.update(|s| s.brightness = brightness);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Calibrate(None) => {
+                let cal = c.shared.led_state.output().calibration();
+                c.shared
+                    .host
+                    .lock(|h| h.reply(format_args!("calibrate {} {} {}", cal.r, cal.g, cal.b)));
+            }
+            HostCommand::Calibrate(Some(calibration)) => {
+                c.shared.led_state.output_mut().set_calibration(calibration);
+                c.shared.settings.update(|s| s.calibration = calibration);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Params { hue, sat, val, speed } => match c.shared.led_state.params() {
+                Some(mut params) => {
+                    params.hue = hue.unwrap_or(params.hue);
+                    params.sat = sat.unwrap_or(params.sat);
+                    params.val = val.unwrap_or(params.val);
+                    params.speed = speed.unwrap_or(params.speed);
+                    c.shared.led_state.set_params(params);
+                    let effect = c.shared.led_state.effect();
+                    c.shared.settings.update(|s| s.effect_params[effect] = Some(params));
+                    c.shared.host.lock(|h| {
+                        h.reply(format_args!(
+                            "params hue={} sat={} val={} speed={}",
+                            params.hue, params.sat, params.val, params.speed
+                        ))
+                    });
+                }
+                None => c.shared.host.lock(|h| h.reply(format_args!("err effect has no params"))),
+            },
+            HostCommand::SetLed { index, color } => {
+                if c.shared.led_state.set_host_led(index, color) {
+                    c.shared.host.lock(|h| h.reply(format_args!("ok")));
+                } else {
+                    c.shared.host.lock(|h| h.reply(format_args!("err no LED {}", index)));
+                }
+            }
+            HostCommand::Frame(colors) => c.shared.led_state.set_host_frame(&colors),
+            HostCommand::ReleaseLeds => {
+                c.shared.led_state.release_host();
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Transition(None) => {
+                let ms = c.shared.led_state.transition_ms();
+                c.shared.host.lock(|h| h.reply(format_args!("transition {} ms", ms)));
+            }
+            HostCommand::Transition(Some(ms)) => {
+                c.shared.led_state.set_transition_ms(ms);
+                c.shared.settings.update(|s| s.led_transition_ms = ms);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Power(None) => {
+                let led_state = &*c.shared.led_state;
+                let estimate_ma = led_output::estimate_current_ma(&led_state.get_grb());
+                let limit_ma = led_state.output().current_limit_ma();
+                let budget_ma = c.shared.settings.get().led_budget_ma;
+                c.shared.host.lock(|h| {
+                    h.reply(format_args!(
+                        "power {} mA, limit {} mA, budget {} mA",
+                        estimate_ma, limit_ma, budget_ma
+                    ))
+                });
+            }
+            HostCommand::Power(Some(budget_ma)) => {
+                // Takes effect on the next scan, once the USB state is known
+                c.shared.settings.update(|s| s.led_budget_ma = budget_ma);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Volume(percent) => {
+                c.shared.display.set_volume(percent);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::Animation(index) => {
+                c.shared.display.play_animation(images::ALL[index]);
+                c.shared.host.lock(|h| h.reply(format_args!("ok")));
+            }
+            HostCommand::SetTime {
+                unix_secs,
+                utc_offset_secs,
+            } => {
+                let now_us = c.shared.timer.lock(|t| PicoClock::new(t).now_us());
+                if c.shared.wall_clock.set(unix_secs, utc_offset_secs, now_us) {
+                    c.shared.host.lock(|h| h.reply(format_args!("ok")));
+                } else {
+                    c.shared.host.lock(|h| h.reply(format_args!("err time out of range")));
+                }
+            }
+        }
+    }
+
+    // Flash writes stall everything for tens of ms, so keep them out of the
+    // scan
+    #[task(priority = 1, shared = [settings])]
+    fn save_settings(c: save_settings::Context) {
+        c.shared.settings.save();
+    }
+
+    #[task(priority = 1, shared = [heatmap])]
+    fn snapshot_heatmap(c: snapshot_heatmap::Context) {
+        c.shared.heatmap.snapshot();
+    }
+
     #[task(
         binds = TIMER_IRQ_0,
         priority = 1,
-        shared = [matrix, debouncer, watchdog, timer, alarm, layout, usb_class, led_driver, led_state, display],
+        shared = [matrix, debouncer, watchdog, timer, alarm, layout, usb_dev, usb_class, led_driver, led_state, display, idle_sleep, settings, diagnostics, host, heatmap, wall_clock],
     )]
     fn scan_timer_irq(mut c: scan_timer_irq::Context) {
-        let timer = c.shared.timer;
-        let alarm = c.shared.alarm;
-        (timer, alarm).lock(|t, a| {
+        let mut timer = c.shared.timer;
+        let mut alarm = c.shared.alarm;
+        (&mut timer, &mut alarm).lock(|t, a| {
             a.clear_interrupt(t);
             let _ = a.schedule(SCAN_TIME_US.microseconds());
         });
+        let now_us = timer.lock(|t| PicoClock::new(t).now_us());
 
         c.shared.watchdog.feed();
-        for event in c.shared.debouncer.events(c.shared.matrix.get().unwrap()) {
+        let keys = c.shared.matrix.get().unwrap();
+        let any_held = keys.0.iter().flatten().any(|k| *k);
+        if let Some(chatter) = c.shared.diagnostics.update(&keys) {
+            c.shared.host.lock(|h| {
+                h.reply(format_args!(
+                    "chatter R{}C{} gap {}ms count {}",
+                    chatter.row, chatter.col, chatter.gap, chatter.count
+                ))
+            });
+        }
+        for event in c.shared.debouncer.events(keys) {
+            c.shared.idle_sleep.handle_keypress();
             if event.is_press() {
-                c.shared.led_state.handle_keypress();
-                c.shared.display.handle_keypress();
+                let (i, j) = event.coord();
+                c.shared.heatmap.handle_keypress(i as usize, j as usize);
+                c.shared
+                    .led_state
+                    .handle_keypress(Some(Position::of_key(i as usize, j as usize)));
+                c.shared.display.handle_keypress(now_us);
             }
             c.shared.layout.lock(|l| l.event(event));
         }
 
-        let mut mode = None;
+        let mut effect = None;
+        let next_effect = (c.shared.led_state.effect() + 1) % effects::COUNT;
+        let mut show_diagnostics = false;
+        let mut next_page = false;
+        let mut param_step = None;
+        let mut brightness = c.shared.led_state.output().brightness();
+        let mut debounce = c.shared.debouncer.config();
 
         c.shared.layout.lock(|l| {
             let custom_action = l.tick();
 
             match custom_action {
-                CustomEvent::Press(CustomActions::SetModeRainbow) => mode = Some(LedMode::Rainbow),
-                CustomEvent::Press(CustomActions::SetModeLightning) => {
-                    mode = Some(LedMode::Lightning)
+                CustomEvent::Press(CustomActions::SetEffect(index)) => effect = Some(*index),
+                CustomEvent::Press(CustomActions::NextEffect) => effect = Some(next_effect),
+                CustomEvent::Press(CustomActions::ParamUp(param)) => param_step = Some((*param, true)),
+                CustomEvent::Press(CustomActions::ParamDown(param)) => {
+                    param_step = Some((*param, false))
                 }
-                CustomEvent::Press(CustomActions::SetModeChase) => mode = Some(LedMode::Chase),
-                CustomEvent::Press(CustomActions::SetModeChase2) => mode = Some(LedMode::Chase2),
                 CustomEvent::Press(CustomActions::RestartToUf2) => {
                     hal::rom_data::reset_to_usb_boot(0, 0)
                 }
+                CustomEvent::Press(CustomActions::DebounceThresholdUp) => debounce.threshold_up(),
+                CustomEvent::Press(CustomActions::DebounceThresholdDown) => {
+                    debounce.threshold_down()
+                }
+                CustomEvent::Press(CustomActions::CycleDebounceAlgorithm) => {
+                    debounce.algorithm = debounce.algorithm.next()
+                }
+                CustomEvent::Press(CustomActions::ShowDiagnostics) => show_diagnostics = true,
+                CustomEvent::Press(CustomActions::NextPage) => next_page = true,
+                CustomEvent::Press(CustomActions::BrightnessUp) => {
+                    brightness = brightness.saturating_add(BRIGHTNESS_STEP)
+                }
+                CustomEvent::Press(CustomActions::BrightnessDown) => {
+                    brightness = brightness.saturating_sub(BRIGHTNESS_STEP)
+                }
                 _ => (),
             }
         });
 
-        match mode {
-            Some(x) => c.shared.led_state.set_mode(x),
+        match effect {
+            Some(x) => {
+                c.shared.led_state.set_effect(x);
+                c.shared.settings.update(|s| s.led_effect = x);
+            }
             None => (),
         }
 
-        let mut media_report = MediaKeyHidReport::default();
+        if show_diagnostics {
+            let offenders = c.shared.diagnostics.worst_offenders::<NUM_DIAG_OFFENDERS>();
+            let mut lines: heapless::Vec<heapless::String<24>, { NUM_DIAG_OFFENDERS + 1 }> =
+                heapless::Vec::new();
+            let _ = lines.push(heapless::String::from("Worst switches:"));
+            for offender in offenders.iter() {
+                let mut line = heapless::String::new();
+                let _ = write!(line, "{}", offender);
+                let _ = lines.push(line);
+            }
+            if offenders.is_empty() {
+                let _ = lines.push(heapless::String::from("None, all good"));
+            }
+
+            let mut text: [&str; NUM_DIAG_OFFENDERS + 1] = [""; NUM_DIAG_OFFENDERS + 1];
+            for (t, line) in text.iter_mut().zip(lines.iter()) {
+                *t = line.as_str();
+            }
+            c.shared.display.show_text(&text[..lines.len()]);
+        }
+
+        if next_page {
+            c.shared.display.next_page();
+        }
+
+        if let Some((param, up)) = param_step {
+            if let Some(mut params) = c.shared.led_state.params() {
+                params.step(param, up);
+                c.shared.led_state.set_params(params);
+                let effect = c.shared.led_state.effect();
+                c.shared.settings.update(|s| s.effect_params[effect] = Some(params));
+            }
+            c.shared.display.show_params(
+                c.shared.led_state.effect_name(),
+                c.shared.led_state.params(),
+                param,
+            );
+        }
+
+        if brightness != c.shared.led_state.output().brightness() {
+            c.shared.led_state.output_mut().set_brightness(brightness);
+            let brightness = c.shared.led_state.output().brightness();
+            c.shared.settings.update(|s| s.brightness = brightness);
+        }
+
+        if debounce != c.shared.debouncer.config() {
+            c.shared.debouncer.set_config(debounce);
+            c.shared.settings.update(|s| s.debounce = debounce);
+        }
+
+        let mut media_key = None;
 
         let kb_report: KbHidReport = c.shared.layout.lock(|l| {
             // Create a media report from the layout keycodes. Note only one media key will be processed at a time.
@@ -377,19 +842,19 @@ mod app {
             // As only one media key should be active at once.
             for item in l.keycodes() {
                 if item == key_code::KeyCode::MediaVolUp {
-                    media_report = MediaKeyHidReport::from(&MediaKey::VolUp);
+                    media_key = Some(MediaKey::VolUp);
                     break;
                 } else if item == key_code::KeyCode::MediaVolDown {
-                    media_report = MediaKeyHidReport::from(&MediaKey::VolDown);
+                    media_key = Some(MediaKey::VolDown);
                     break;
                 } else if item == key_code::KeyCode::MediaPlayPause {
-                    media_report = MediaKeyHidReport::from(&MediaKey::PlayPause);
+                    media_key = Some(MediaKey::PlayPause);
                     break;
                 } else if item == key_code::KeyCode::MediaNextSong {
-                    media_report = MediaKeyHidReport::from(&MediaKey::NextTrack);
+                    media_key = Some(MediaKey::NextTrack);
                     break;
                 } else if item == key_code::KeyCode::MediaPreviousSong {
-                    media_report = MediaKeyHidReport::from(&MediaKey::PrevTrack);
+                    media_key = Some(MediaKey::PrevTrack);
                     break;
                 }
                 
@@ -397,6 +862,7 @@ mod app {
 
             l.keycodes().collect()
         });
+        let media_report = media_key.as_ref().map_or(MediaKeyHidReport::default(), MediaKeyHidReport::from);
 
         // Send media key report, assembled from keycodes from out layout. Note media keys must be processed separate to
         // normal keycodes.
@@ -404,7 +870,10 @@ mod app {
             .usb_class
             .lock(|k| k.device_mut().set_media_report(media_report.clone()))
         {
-            c.shared.led_state.handle_keypress();
+            c.shared.led_state.handle_keypress(None);
+            if let Some(key) = media_key {
+                c.shared.display.show_media_key(key);
+            }
             while let Ok(0) = c
                 .shared
                 .usb_class
@@ -421,11 +890,90 @@ mod app {
         }
 
         // Update display
-        c.shared.display.tick();
+        let layer = c.shared.layout.lock(|l| l.current_layer());
+        c.shared.display.set_layer(layer, LAYER_NAMES[layer]);
+        c.shared.led_state.set_layer(layer);
+        let locks = c.shared.usb_class.lock(|k| k.device().leds());
+        c.shared.display.set_locks(locks);
+        c.shared.led_state.set_locks(locks);
+        let usb_state = c.shared.usb_dev.lock(|d| d.state());
+        c.shared.display.set_usb_configured(usb_state == UsbDeviceState::Configured);
+        c.shared.display.set_time(c.shared.wall_clock.local_time(now_us));
+        c.shared
+            .display
+            .set_status(c.shared.led_state.effect_name(), c.shared.debouncer.config());
+        c.shared.display.tick(now_us);
+
+        if c.shared.settings.tick() {
+            // Already queued if this fails
+            let _ = save_settings::spawn();
+        }
+        if c.shared.heatmap.tick() {
+            let _ = snapshot_heatmap::spawn();
+        }
+        let heatmap = &mut *c.shared.heatmap;
+        c.shared.host.lock(|h| {
+            while heatmap.is_exporting()
+                && h.tx_space() >= Heatmap::<NUM_COLUMNS, NUM_ROWS>::MAX_EXPORT_PIECE_LEN
+            {
+                let _ = heatmap.export_next(h);
+                h.flush();
+            }
+            h.flush();
+        });
 
         // Update led states
-        c.shared.led_state.tick();
+        if c.shared.led_state.is_showing(effects::HEATMAP) {
+            let levels = c.shared.heatmap.led_levels(c.shared.led_state.map());
+            c.shared.led_state.set_heat_levels(levels);
+        }
+        let led_budget_ma = match usb_state {
+            UsbDeviceState::Configured => c.shared.settings.get().led_budget_ma,
+            UsbDeviceState::Suspend => SUSPENDED_LED_BUDGET_MA,
+            _ => UNCONFIGURED_LED_BUDGET_MA,
+        };
+        c.shared.led_state.output_mut().set_current_limit_ma(led_budget_ma);
+        c.shared.led_state.tick(now_us);
         let data = c.shared.led_state.get_grb();
         c.shared.led_driver.write(data.iter().copied()).unwrap();
+
+        // Go to sleep once idle. A held key (e.g. something resting on the
+        // keyboard) keeps us awake, as it would never generate a wake edge.
+        if c.shared.idle_sleep.tick() && !any_held {
+            c.shared.led_driver
+                .write([RGB8::default(); NUM_LEDS].iter().copied())
+                .unwrap();
+            c.shared.display.set_power(false);
+            c.shared.matrix.drive_all_low().unwrap();
+            c.shared.heatmap.snapshot();
+
+            // Nothing feeds the watchdog while the scan alarm is stopped
+            c.shared.watchdog.disable();
+            (&mut timer, &mut alarm).lock(|t, a| a.disable_interrupt(t));
+            c.shared.idle_sleep.sleep();
+        }
+    }
+
+    #[task(
+        binds = IO_IRQ_BANK0,
+        priority = 1,
+        shared = [matrix, watchdog, timer, alarm, display, idle_sleep],
+    )]
+    fn wake_irq(c: wake_irq::Context) {
+        if !c.shared.idle_sleep.wake() {
+            return;
+        }
+
+        c.shared.matrix.clear().unwrap();
+        c.shared.display.set_power(true);
+        c.shared.watchdog.start(WATCHDOG_TIMEOUT_US.microseconds());
+
+        // Resume scanning from the next period. The key that woke us is still
+        // held by then, so its press is picked up as usual.
+        (c.shared.timer, c.shared.alarm).lock(|t, a| {
+            a.clear_interrupt(t);
+            let _ = a.schedule(SCAN_TIME_US.microseconds());
+            a.enable_interrupt(t);
+        });
     }
 }
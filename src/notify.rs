@@ -0,0 +1,99 @@
+//! Notifications pushed by the host.
+//!
+//! Each notification has an id chosen by the host, so sending the same id
+//! again updates it in place (a song title, an unread count). Higher
+//! priorities are shown first, and a notification can expire after a while.
+
+use crate::animation::Animation;
+use crate::images;
+
+pub const MAX_NOTIFICATIONS: usize = 4;
+pub const NOTIFY_TEXT_LEN: usize = 64;
+pub const MAX_PRIORITY: u8 = 9;
+/// Notifications at or above this priority stay on screen until a keypress
+pub const URGENT_PRIORITY: u8 = 7;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Notification {
+    pub id: u8,
+    /// 0 is shown without popping up, up to `MAX_PRIORITY`
+    pub priority: u8,
+    /// Time to live, forever if `None`. Made absolute when the notification is
+    /// added.
+    pub expires_us: Option<u64>,
+    /// Index into `images::ALL`
+    pub icon: Option<usize>,
+    /// Percent
+    pub progress: Option<u8>,
+    pub text: heapless::String<NOTIFY_TEXT_LEN>,
+}
+
+impl Notification {
+    pub fn icon(&self) -> Option<&'static Animation> {
+        self.icon.and_then(|i| images::ALL.get(i).copied())
+    }
+}
+
+pub struct Notifications {
+    // Kept sorted, highest priority first
+    list: heapless::Vec<Notification, MAX_NOTIFICATIONS>,
+}
+
+impl Notifications {
+    pub fn new() -> Self {
+        Self {
+            list: heapless::Vec::new(),
+        }
+    }
+
+    /// Add or replace a notification. If the list is full the lowest priority
+    /// one is dropped, which may be the new one.
+    pub fn add(&mut self, mut notification: Notification, now_us: u64) {
+        notification.expires_us = notification.expires_us.map(|ttl| now_us + ttl);
+        self.remove(notification.id);
+
+        if self.list.is_full() {
+            if self.list.last().map_or(false, |n| n.priority > notification.priority) {
+                return;
+            }
+            self.list.pop();
+        }
+        let _ = self.list.push(notification);
+
+        // Move it up past anything of lower priority, behind older ones of
+        // the same priority
+        let mut i = self.list.len() - 1;
+        while i > 0 && self.list[i - 1].priority < self.list[i].priority {
+            self.list.swap(i - 1, i);
+            i -= 1;
+        }
+    }
+
+    fn retain<F: FnMut(&Notification) -> bool>(&mut self, f: F) {
+        let list = core::mem::take(&mut self.list);
+        self.list = list.into_iter().filter(f).collect();
+    }
+
+    pub fn remove(&mut self, id: u8) {
+        self.retain(|n| n.id != id);
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    /// Drop expired notifications, returns true if any were removed
+    pub fn expire(&mut self, now_us: u64) -> bool {
+        let len = self.list.len();
+        self.retain(|n| n.expires_us.map_or(true, |t| now_us < t));
+        self.list.len() != len
+    }
+
+    pub fn top(&self) -> Option<&Notification> {
+        self.list.first()
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+}
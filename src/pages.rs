@@ -0,0 +1,914 @@
+//! The pages shown by `CaeDisplay`.
+//!
+//! Pages lay themselves out from the size of the draw target, so they work
+//! on 128x64 and 128x32 panels, in landscape or portrait.
+
+use crate::animation::{Animation, AnimationPlayer};
+use crate::clock::DateTime;
+use crate::effects::{Param, Params};
+use crate::images;
+use crate::keyboard::MediaKey;
+use crate::notify::Notifications;
+use crate::screen::{Page, PageContext, PageId, PageUpdate};
+use core::fmt::Write;
+use embedded_graphics::{
+    image::Image,
+    mono_font::{
+        ascii::{FONT_4X6, FONT_6X10, FONT_9X18_BOLD},
+        MonoTextStyle, MonoTextStyleBuilder,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+
+// The bongo_tap frame period is the one used at 60 WPM, scaled inversely with
+// the current WPM
+const BONGO_FRAME_MIN_US: u64 = 50_000;
+const BONGO_FRAME_MAX_US: u64 = 500_000;
+
+// How long the splash stays up once USB is configured, and how long to wait
+// for USB before reporting an error
+const SPLASH_HOLD_US: u64 = 1_000_000;
+const SPLASH_USB_TIMEOUT_US: u64 = 5_000_000;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &str = env!("GIT_HASH");
+const BUILD_DATE: &str = env!("BUILD_DATE");
+
+// Volume change per key press, for estimating the level until the host reports
+// the real one
+const VOLUME_STEP: u8 = 5;
+
+pub const TEXT_LINES: usize = 6;
+pub const TEXT_LINE_LEN: usize = 21;
+
+fn small_text() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(&FONT_6X10)
+        .text_color(BinaryColor::On)
+        .build()
+}
+
+fn large_text() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(&FONT_9X18_BOLD)
+        .text_color(BinaryColor::On)
+        .build()
+}
+
+fn tiny_text() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(&FONT_4X6)
+        .text_color(BinaryColor::On)
+        .build()
+}
+
+fn target_size<D: DrawTarget>(target: &D) -> Size {
+    target.bounding_box().size
+}
+
+/// Layer name in a small inverted box in the top right corner
+pub fn draw_layer_badge<D>(ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let size = target_size(target);
+    let char_size = FONT_4X6.character_size;
+    let width = char_size.width * ctx.layer_name.len() as u32 + 4;
+    let badge = Rectangle::new(
+        Point::new(size.width as i32 - width as i32, 0),
+        Size::new(width, char_size.height + 2),
+    );
+    badge
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+
+    let style = MonoTextStyleBuilder::new()
+        .font(&FONT_4X6)
+        .text_color(BinaryColor::Off)
+        .build();
+    Text::with_baseline(ctx.layer_name, badge.top_left + Point::new(2, 1), style, Baseline::Top)
+        .draw(target)?;
+    Ok(())
+}
+
+/// Draw lines of small text from the top, as many as fit
+fn draw_lines<'a, D, L>(target: &mut D, lines: L) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    L: IntoIterator<Item = &'a str>,
+{
+    let line_height = FONT_6X10.character_size.height;
+    let max_lines = target_size(target).height / line_height;
+
+    for (i, line) in lines.into_iter().take(max_lines as usize).enumerate() {
+        let y = i as i32 * line_height as i32;
+        Text::with_baseline(line, Point::new(0, y), small_text(), Baseline::Top).draw(target)?;
+    }
+    Ok(())
+}
+
+pub struct BongoPage {
+    tap: AnimationPlayer,
+    tapping: bool,
+    shown_wpm: u16,
+}
+
+impl BongoPage {
+    pub fn new() -> Self {
+        Self {
+            tap: AnimationPlayer::new(&images::BONGO_TAP),
+            tapping: false,
+            shown_wpm: 0,
+        }
+    }
+
+    fn frame_us(wpm: u16) -> u64 {
+        let frame_us = images::BONGO_TAP.frame_us * 60 / wpm.max(1) as u64;
+        frame_us.max(BONGO_FRAME_MIN_US).min(BONGO_FRAME_MAX_US)
+    }
+}
+
+impl Page for BongoPage {
+    fn enter(&mut self, ctx: &PageContext) {
+        self.shown_wpm = ctx.wpm;
+        self.tapping = ctx.wpm > 0;
+    }
+
+    fn handle_keypress(&mut self, ctx: &PageContext) -> PageUpdate {
+        // Start tapping straight away, rather than waiting for the WPM to
+        // catch up
+        if self.tapping {
+            return PageUpdate::Idle;
+        }
+        self.tapping = true;
+        self.tap.step(ctx.now_us);
+        PageUpdate::Redraw
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        let mut update = PageUpdate::Idle;
+
+        if ctx.wpm != self.shown_wpm {
+            self.shown_wpm = ctx.wpm;
+            update = PageUpdate::Redraw;
+        }
+
+        if ctx.wpm == 0 {
+            if self.tapping {
+                self.tapping = false;
+                update = PageUpdate::Redraw;
+            }
+        } else if self.tap.tick(ctx.now_us, Self::frame_us(ctx.wpm)) || !self.tapping {
+            self.tapping = true;
+            update = PageUpdate::Redraw;
+        }
+
+        return update;
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let size = target_size(target);
+        let text_height = FONT_6X10.character_size.height;
+
+        // Centre the cat horizontally. If there's room, the WPM goes below
+        // it, otherwise the cat sits on the bottom edge (cropping its ears)
+        // and the WPM goes in the top right corner.
+        let frame = if self.tapping {
+            self.tap.frame()
+        } else {
+            images::BONGO_IDLE.frame(0)
+        };
+        let x = (size.width as i32 - frame.size().width as i32) / 2;
+        let below = size.height >= frame.size().height + text_height;
+        let y = if below {
+            0
+        } else {
+            size.height as i32 - frame.size().height as i32
+        };
+        Image::new(&frame, Point::new(x, y)).draw(target)?;
+
+        let mut text: heapless::String<12> = heapless::String::new();
+        let _ = write!(text, "{} WPM", self.shown_wpm);
+        if below {
+            Text::with_baseline(text.as_str(), Point::new(0, size.height as i32), small_text(), Baseline::Bottom)
+                .draw(target)?;
+        } else {
+            Text::with_alignment(text.as_str(), Point::new(size.width as i32, 0), small_text(), Alignment::Right)
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct LayerPage {
+    shown_layer: usize,
+}
+
+impl Page for LayerPage {
+    fn enter(&mut self, ctx: &PageContext) {
+        self.shown_layer = ctx.layer;
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        if ctx.layer == self.shown_layer {
+            return PageUpdate::Idle;
+        }
+        self.shown_layer = ctx.layer;
+        PageUpdate::Redraw
+    }
+
+    fn render<D>(&self, ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let size = target_size(target);
+        let mut text: heapless::String<12> = heapless::String::new();
+        let _ = write!(text, "Layer {}", ctx.layer);
+
+        // Two lines in the large font if they fit, small otherwise
+        let style = if size.height >= 2 * FONT_9X18_BOLD.character_size.height {
+            large_text()
+        } else {
+            small_text()
+        };
+        let line_height = style.font.character_size.height as i32;
+        let top = (size.height as i32 - 2 * line_height) / 2;
+
+        Text::with_baseline(text.as_str(), Point::new(0, top), style, Baseline::Top)
+            .draw(target)?;
+        Text::with_baseline(ctx.layer_name, Point::new(0, top + line_height), style, Baseline::Top)
+            .draw(target)?;
+        Ok(())
+    }
+}
+
+pub struct LocksPage {
+    shown_locks: crate::keyboard::KeyboardLeds,
+}
+
+impl Page for LocksPage {
+    fn enter(&mut self, ctx: &PageContext) {
+        self.shown_locks = ctx.locks;
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        if ctx.locks == self.shown_locks {
+            return PageUpdate::Idle;
+        }
+        self.shown_locks = ctx.locks;
+        PageUpdate::Redraw
+    }
+
+    fn render<D>(&self, ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        // Icons for the active locks, an empty outline for the rest
+        let locks = [
+            (ctx.locks.caps_lock(), &images::CAP_ON),
+            (ctx.locks.num_lock(), &images::NUM_ON),
+            (ctx.locks.scroll_lock(), &images::SCR_ON),
+        ];
+        // In a row if they fit across, otherwise stacked
+        let size = target_size(target);
+        let icon_size = images::CAP_ON.size;
+        let across = size.width >= icon_size.width * 3;
+        for (i, (on, icon)) in locks.iter().enumerate() {
+            let i = i as i32;
+            let position = if across {
+                let gap = (size.width - icon_size.width * 3) as i32 / 3;
+                Point::new(
+                    gap / 2 + i * (icon_size.width as i32 + gap),
+                    size.height.saturating_sub(icon_size.height) as i32 / 2,
+                )
+            } else {
+                let gap = size.height.saturating_sub(icon_size.height * 3) as i32 / 3;
+                Point::new(
+                    (size.width as i32 - icon_size.width as i32) / 2,
+                    gap / 2 + i * (icon_size.height as i32 + gap),
+                )
+            };
+            if *on {
+                Image::new(&icon.frame(0), position).draw(target)?;
+            } else {
+                Rectangle::new(position, icon_size)
+                    .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                    .draw(target)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct StatusPage {
+    shown_secs: u64,
+}
+
+impl Page for StatusPage {
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        // Redraw once a second for the uptime, which also picks up any other
+        // changes
+        let secs = ctx.now_us / 1_000_000;
+        if secs == self.shown_secs {
+            return PageUpdate::Idle;
+        }
+        self.shown_secs = secs;
+        PageUpdate::Redraw
+    }
+
+    fn render<D>(&self, ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut lines: [heapless::String<TEXT_LINE_LEN>; 5] = Default::default();
+        let _ = write!(lines[0], "Layer: {}", ctx.layer_name);
+        let _ = write!(lines[1], "LEDs:  {}", ctx.led_effect_name);
+        let _ = write!(
+            lines[2],
+            "Deb:   {} {}ms",
+            ctx.debounce.algorithm.name(),
+            ctx.debounce.threshold
+        );
+        let _ = write!(lines[3], "WPM:   {}", ctx.wpm);
+        let secs = ctx.now_us / 1_000_000;
+        let _ = write!(
+            lines[4],
+            "Up:    {}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+
+        draw_lines(target, lines.iter().map(|l| l.as_str()))
+    }
+}
+
+pub struct TextPage {
+    lines: heapless::Vec<heapless::String<TEXT_LINE_LEN>, TEXT_LINES>,
+}
+
+impl TextPage {
+    pub fn set_lines(&mut self, lines: &[&str]) {
+        self.lines.clear();
+        for line in lines.iter().take(TEXT_LINES) {
+            let mut text = heapless::String::new();
+            // Cut long lines to fit the display
+            for c in line.chars() {
+                if text.push(c).is_err() {
+                    break;
+                }
+            }
+            let _ = self.lines.push(text);
+        }
+    }
+}
+
+impl Page for TextPage {
+    fn handle_keypress(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Exit
+    }
+
+    fn update(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Idle
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        draw_lines(target, self.lines.iter().map(|l| l.as_str()))
+    }
+}
+
+/// Plays an animation for one pass, or until a keypress
+pub struct AnimationPage {
+    player: AnimationPlayer,
+    started_us: u64,
+}
+
+impl AnimationPage {
+    pub fn set_animation(&mut self, animation: &'static Animation) {
+        self.player = AnimationPlayer::new(animation);
+    }
+}
+
+impl Page for AnimationPage {
+    fn enter(&mut self, ctx: &PageContext) {
+        self.player.play(self.player.animation(), ctx.now_us);
+        self.started_us = ctx.now_us;
+    }
+
+    fn handle_keypress(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Exit
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        let animation = self.player.animation();
+        if ctx.now_us - self.started_us >= animation.duration_us() {
+            return PageUpdate::Exit;
+        }
+        if self.player.tick(ctx.now_us, animation.frame_us) {
+            return PageUpdate::Redraw;
+        }
+        PageUpdate::Idle
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let size = target_size(target);
+        let frame = self.player.frame();
+        let position = Point::new(
+            (size.width as i32 - frame.size().width as i32) / 2,
+            (size.height as i32 - frame.size().height as i32) / 2,
+        );
+        Image::new(&frame, position).draw(target)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum UsbStatus {
+    Waiting,
+    Configured,
+    TimedOut,
+}
+
+/// Boot splash: logo, firmware info and USB enumeration status. Hands over
+/// to the home page shortly after USB is configured, or on a keypress.
+pub struct SplashPage {
+    usb: UsbStatus,
+    configured_us: u64,
+}
+
+impl Page for SplashPage {
+    fn handle_keypress(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Exit
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        match self.usb {
+            UsbStatus::Configured => {
+                if ctx.now_us - self.configured_us >= SPLASH_HOLD_US {
+                    return PageUpdate::Exit;
+                }
+                PageUpdate::Idle
+            }
+            _ if ctx.usb_configured => {
+                self.usb = UsbStatus::Configured;
+                self.configured_us = ctx.now_us;
+                PageUpdate::Redraw
+            }
+            UsbStatus::Waiting if ctx.now_us >= SPLASH_USB_TIMEOUT_US => {
+                self.usb = UsbStatus::TimedOut;
+                PageUpdate::Redraw
+            }
+            _ => PageUpdate::Idle,
+        }
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut version: heapless::String<24> = heapless::String::new();
+        let _ = write!(version, "v{} {}", VERSION, GIT_HASH);
+        let usb = match self.usb {
+            UsbStatus::Waiting => "USB: waiting...",
+            UsbStatus::Configured => "USB: configured",
+            UsbStatus::TimedOut => "USB: ERROR, not enumerated",
+        };
+
+        // Logo with two lines of tiny text below if it fits, otherwise just
+        // the text
+        let size = target_size(target);
+        let logo = images::LOGO.frame(0);
+        let line_height = FONT_4X6.character_size.height;
+        if size.height >= logo.size().height + 2 * line_height {
+            let x = (size.width as i32 - logo.size().width as i32) / 2;
+            Image::new(&logo, Point::new(x, 0)).draw(target)?;
+            let y = (size.height - 2 * line_height) as i32;
+            Text::with_baseline(version.as_str(), Point::new(0, y), tiny_text(), Baseline::Top)
+                .draw(target)?;
+            Text::with_alignment(BUILD_DATE, Point::new(size.width as i32, y), tiny_text(), Alignment::Right)
+                .draw(target)?;
+            Text::with_baseline(usb, Point::new(0, y + line_height as i32), tiny_text(), Baseline::Top)
+                .draw(target)?;
+            Ok(())
+        } else {
+            draw_lines(target, [version.as_str(), BUILD_DATE, usb])
+        }
+    }
+}
+
+/// The highest priority notification, with its icon and progress bar
+pub struct NotifyPage {
+    pub notifications: Notifications,
+}
+
+impl Page for NotifyPage {
+    fn handle_keypress(&mut self, _ctx: &PageContext) -> PageUpdate {
+        // Dismisses pop-ups, does nothing as the home page
+        PageUpdate::Exit
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        if !self.notifications.expire(ctx.now_us) {
+            return PageUpdate::Idle;
+        }
+        if self.notifications.top().is_none() {
+            return PageUpdate::Exit;
+        }
+        PageUpdate::Redraw
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let notification = match self.notifications.top() {
+            Some(notification) => notification,
+            None => return draw_lines(target, ["No notifications"]),
+        };
+        let size = target_size(target);
+
+        let mut x = 0;
+        if let Some(icon) = notification.icon() {
+            let frame = icon.frame(0);
+            Image::new(&frame, Point::zero()).draw(target)?;
+            x = frame.size().width + 4;
+        }
+
+        // Count of the others waiting behind this one
+        if self.notifications.len() > 1 {
+            let mut more: heapless::String<4> = heapless::String::new();
+            let _ = write!(more, "+{}", self.notifications.len() - 1);
+            Text::with_alignment(more.as_str(), Point::new(size.width as i32, 0), tiny_text(), Alignment::Right)
+                .draw(target)?;
+        }
+
+        let mut bottom = size.height;
+        if let Some(percent) = notification.progress {
+            bottom = size.height.saturating_sub(10);
+            let bar = Rectangle::new(Point::new(0, bottom as i32 + 2), Size::new(size.width, 8));
+            bar.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                .draw(target)?;
+            let fill = (size.width - 4) * percent as u32 / 100;
+            Rectangle::new(bar.top_left + Point::new(2, 2), Size::new(fill, 4))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(target)?;
+        }
+
+        // Wrap the text into whatever space is left
+        let char_size = FONT_6X10.character_size;
+        let line_len = ((size.width.saturating_sub(x)) / char_size.width).max(1) as usize;
+        let mut text = notification.text.as_str();
+        let mut y = 0;
+        while !text.is_empty() && y + char_size.height <= bottom {
+            let split = text.char_indices().nth(line_len).map_or(text.len(), |(i, _)| i);
+            Text::with_baseline(&text[..split], Point::new(x as i32, y as i32), small_text(), Baseline::Top)
+                .draw(target)?;
+            text = text[split..].trim_start();
+            y += char_size.height;
+        }
+        Ok(())
+    }
+}
+
+pub struct ClockPage {
+    shown: Option<DateTime>,
+}
+
+impl Page for ClockPage {
+    fn enter(&mut self, ctx: &PageContext) {
+        self.shown = ctx.time;
+    }
+
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate {
+        if ctx.time == self.shown {
+            return PageUpdate::Idle;
+        }
+        self.shown = ctx.time;
+        PageUpdate::Redraw
+    }
+
+    fn render<D>(&self, ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let time = match ctx.time {
+            Some(time) => time,
+            None => return draw_lines(target, ["Time not set", "", "Sync it from the host", "with `time`"]),
+        };
+
+        let mut hms: heapless::String<8> = heapless::String::new();
+        let _ = write!(hms, "{:02}:{:02}:{:02}", time.hour, time.minute, time.second);
+        let mut date: heapless::String<16> = heapless::String::new();
+        let _ = write!(
+            date,
+            "{} {}-{:02}-{:02}",
+            time.weekday_name(),
+            time.year,
+            time.month,
+            time.day
+        );
+
+        // Time in the large font with the date below, centred
+        let size = target_size(target);
+        let large_height = FONT_9X18_BOLD.character_size.height as i32;
+        let top = (size.height as i32 - large_height - 2 - FONT_6X10.character_size.height as i32) / 2;
+        let centred = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Top)
+            .build();
+        let x = size.width as i32 / 2;
+        Text::with_text_style(hms.as_str(), Point::new(x, top), large_text(), centred).draw(target)?;
+        Text::with_text_style(date.as_str(), Point::new(x, top + large_height + 2), small_text(), centred)
+            .draw(target)?;
+        Ok(())
+    }
+}
+
+/// Feedback for the last media key: its icon, and for volume keys the level
+pub struct MediaPage {
+    key: MediaKey,
+    volume: u8,
+    // Whether `volume` came from the host, or is just an estimate
+    volume_known: bool,
+}
+
+impl MediaPage {
+    /// Record a media key press, stepping the volume for volume keys. The
+    /// level is an estimate again until the host reports it.
+    pub fn press(&mut self, key: MediaKey) {
+        self.key = key;
+        match key {
+            MediaKey::VolUp => {
+                self.volume = (self.volume + VOLUME_STEP).min(100);
+                self.volume_known = false;
+            }
+            MediaKey::VolDown => {
+                self.volume = self.volume.saturating_sub(VOLUME_STEP);
+                self.volume_known = false;
+            }
+            _ => (),
+        }
+    }
+
+    /// The real level, as reported by the host
+    pub fn set_volume(&mut self, percent: u8) {
+        self.volume = percent.min(100);
+        self.volume_known = true;
+    }
+
+    fn icon(&self) -> Option<&'static Animation> {
+        match self.key {
+            MediaKey::VolUp => Some(&images::MEDIA_VOL_UP),
+            MediaKey::VolDown => Some(&images::MEDIA_VOL_DOWN),
+            MediaKey::PlayPause => Some(&images::MEDIA_PLAY_PAUSE),
+            MediaKey::NextTrack => Some(&images::MEDIA_NEXT),
+            MediaKey::PrevTrack => Some(&images::MEDIA_PREV),
+            _ => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self.key {
+            MediaKey::VolUp | MediaKey::VolDown => "Volume",
+            MediaKey::PlayPause => "Play/Pause",
+            MediaKey::NextTrack => "Next",
+            MediaKey::PrevTrack => "Previous",
+            MediaKey::Stop => "Stop",
+            _ => "Media",
+        }
+    }
+}
+
+impl Page for MediaPage {
+    fn update(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Idle
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let size = target_size(target);
+
+        // Icon on the left, vertically centred
+        let mut x = 0;
+        if let Some(icon) = self.icon() {
+            let frame = icon.frame(0);
+            let y = (size.height as i32 - frame.size().height as i32) / 2;
+            Image::new(&frame, Point::new(0, y)).draw(target)?;
+            x = frame.size().width as i32 + 6;
+        }
+
+        let text_height = FONT_6X10.character_size.height as i32;
+        let volume = self.key == MediaKey::VolUp || self.key == MediaKey::VolDown;
+        if !volume {
+            let y = (size.height as i32 - text_height) / 2;
+            Text::with_baseline(self.label(), Point::new(x, y), small_text(), Baseline::Top).draw(target)?;
+            return Ok(());
+        }
+
+        // Level as text above a bar, "~" while it's only an estimate
+        let mut text: heapless::String<8> = heapless::String::new();
+        let _ = write!(text, "{}{}%", if self.volume_known { "" } else { "~" }, self.volume);
+        let top = (size.height as i32 - text_height - 10) / 2;
+        Text::with_baseline(text.as_str(), Point::new(x, top), small_text(), Baseline::Top).draw(target)?;
+
+        let width = (size.width as i32 - x).max(4) as u32;
+        let bar = Rectangle::new(Point::new(x, top + text_height + 2), Size::new(width, 8));
+        bar.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(target)?;
+        let fill = (width - 4) * self.volume as u32 / 100;
+        Rectangle::new(bar.top_left + Point::new(2, 2), Size::new(fill, 4))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)?;
+        Ok(())
+    }
+}
+
+/// The LED effect parameter being adjusted, as a number and a bar
+pub struct ParamsPage {
+    effect: &'static str,
+    /// `None` if the effect has nothing to adjust
+    params: Option<Params>,
+    param: Param,
+}
+
+impl ParamsPage {
+    pub fn set(&mut self, effect: &'static str, params: Option<Params>, param: Param) {
+        self.effect = effect;
+        self.params = params;
+        self.param = param;
+    }
+}
+
+impl Page for ParamsPage {
+    fn update(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Idle
+    }
+
+    fn render<D>(&self, _ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let size = target_size(target);
+        let text_height = FONT_6X10.character_size.height as i32;
+        // Effect name, parameter and value, then the bar
+        let top = (size.height as i32 - 2 * text_height - 10).max(0) / 2;
+        Text::with_baseline(self.effect, Point::new(0, top), small_text(), Baseline::Top).draw(target)?;
+
+        let y = top + text_height;
+        let params = match self.params {
+            Some(params) => params,
+            None => {
+                Text::with_baseline("Nothing to adjust", Point::new(0, y), small_text(), Baseline::Top)
+                    .draw(target)?;
+                return Ok(());
+            }
+        };
+
+        let value = params.get(self.param);
+        let mut text: heapless::String<16> = heapless::String::new();
+        let _ = write!(text, "{} {}", self.param.name(), value);
+        Text::with_baseline(text.as_str(), Point::new(0, y), small_text(), Baseline::Top).draw(target)?;
+
+        let bar = Rectangle::new(Point::new(0, y + text_height + 2), Size::new(size.width, 8));
+        bar.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(target)?;
+        let fill = (size.width - 4) * value as u32 / 255;
+        Rectangle::new(bar.top_left + Point::new(2, 2), Size::new(fill, 4))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)?;
+        Ok(())
+    }
+}
+
+/// All pages, dispatched by `PageId`
+pub struct Pages {
+    pub bongo: BongoPage,
+    pub layer: LayerPage,
+    pub locks: LocksPage,
+    pub status: StatusPage,
+    pub text: TextPage,
+    pub animation: AnimationPage,
+    pub splash: SplashPage,
+    pub notify: NotifyPage,
+    pub clock: ClockPage,
+    pub media: MediaPage,
+    pub params: ParamsPage,
+}
+
+impl Pages {
+    pub fn new() -> Self {
+        Self {
+            bongo: BongoPage::new(),
+            layer: LayerPage { shown_layer: 0 },
+            locks: LocksPage {
+                shown_locks: Default::default(),
+            },
+            status: StatusPage { shown_secs: 0 },
+            text: TextPage {
+                lines: heapless::Vec::new(),
+            },
+            animation: AnimationPage {
+                player: AnimationPlayer::new(&images::LOGO),
+                started_us: 0,
+            },
+            splash: SplashPage {
+                usb: UsbStatus::Waiting,
+                configured_us: 0,
+            },
+            notify: NotifyPage {
+                notifications: Notifications::new(),
+            },
+            clock: ClockPage { shown: None },
+            media: MediaPage {
+                key: MediaKey::PlayPause,
+                volume: 50,
+                volume_known: false,
+            },
+            params: ParamsPage {
+                effect: "",
+                params: None,
+                param: Param::Hue,
+            },
+        }
+    }
+
+    pub fn enter(&mut self, id: PageId, ctx: &PageContext) {
+        match id {
+            PageId::Bongo => self.bongo.enter(ctx),
+            PageId::Layer => self.layer.enter(ctx),
+            PageId::Locks => self.locks.enter(ctx),
+            PageId::Status => self.status.enter(ctx),
+            PageId::Text => self.text.enter(ctx),
+            PageId::Animation => self.animation.enter(ctx),
+            PageId::Splash => self.splash.enter(ctx),
+            PageId::Notify => self.notify.enter(ctx),
+            PageId::Clock => self.clock.enter(ctx),
+            PageId::Media => self.media.enter(ctx),
+            PageId::Params => self.params.enter(ctx),
+        }
+    }
+
+    pub fn handle_keypress(&mut self, id: PageId, ctx: &PageContext) -> PageUpdate {
+        match id {
+            PageId::Bongo => self.bongo.handle_keypress(ctx),
+            PageId::Layer => self.layer.handle_keypress(ctx),
+            PageId::Locks => self.locks.handle_keypress(ctx),
+            PageId::Status => self.status.handle_keypress(ctx),
+            PageId::Text => self.text.handle_keypress(ctx),
+            PageId::Animation => self.animation.handle_keypress(ctx),
+            PageId::Splash => self.splash.handle_keypress(ctx),
+            PageId::Notify => self.notify.handle_keypress(ctx),
+            PageId::Clock => self.clock.handle_keypress(ctx),
+            PageId::Media => self.media.handle_keypress(ctx),
+            PageId::Params => self.params.handle_keypress(ctx),
+        }
+    }
+
+    pub fn update(&mut self, id: PageId, ctx: &PageContext) -> PageUpdate {
+        match id {
+            PageId::Bongo => self.bongo.update(ctx),
+            PageId::Layer => self.layer.update(ctx),
+            PageId::Locks => self.locks.update(ctx),
+            PageId::Status => self.status.update(ctx),
+            PageId::Text => self.text.update(ctx),
+            PageId::Animation => self.animation.update(ctx),
+            PageId::Splash => self.splash.update(ctx),
+            PageId::Notify => self.notify.update(ctx),
+            PageId::Clock => self.clock.update(ctx),
+            PageId::Media => self.media.update(ctx),
+            PageId::Params => self.params.update(ctx),
+        }
+    }
+
+    pub fn render<D>(&self, id: PageId, ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        match id {
+            PageId::Bongo => self.bongo.render(ctx, target),
+            PageId::Layer => self.layer.render(ctx, target),
+            PageId::Locks => self.locks.render(ctx, target),
+            PageId::Status => self.status.render(ctx, target),
+            PageId::Text => self.text.render(ctx, target),
+            PageId::Animation => self.animation.render(ctx, target),
+            PageId::Splash => self.splash.render(ctx, target),
+            PageId::Notify => self.notify.render(ctx, target),
+            PageId::Clock => self.clock.render(ctx, target),
+            PageId::Media => self.media.render(ctx, target),
+            PageId::Params => self.params.render(ctx, target),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Screen framework for the OLED.
+//!
+//! The display shows one page at a time. Pages get update and render hooks,
+//! and the `Screen` state machine decides which page is current: the user
+//! picks a home page, and other pages can be shown temporarily, expiring back
+//! to the home page after a set time. All timing is in microseconds from
+//! `PicoClock`, not in scan ticks.
+
+use crate::clock::DateTime;
+use crate::debounce::DebounceConfig;
+use crate::keyboard::KeyboardLeds;
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::DrawTarget};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PageId {
+    Bongo,
+    Layer,
+    Locks,
+    Status,
+    /// Free text, used for diagnostics reports
+    Text,
+    /// One of the generated animations
+    Animation,
+    /// Logo and firmware info, shown from boot until USB is configured
+    Splash,
+    /// Notifications pushed by the host
+    Notify,
+    /// Wall clock time, once the host has set it
+    Clock,
+    /// Overlay for the last media key
+    Media,
+    /// LED effect parameters, while they're being adjusted
+    Params,
+}
+
+impl PageId {
+    /// Pages the user can cycle through as the home page
+    pub const HOME_PAGES: [PageId; 6] = [
+        PageId::Bongo,
+        PageId::Layer,
+        PageId::Locks,
+        PageId::Status,
+        PageId::Notify,
+        PageId::Clock,
+    ];
+}
+
+/// State shared with every page
+#[derive(Clone, Copy)]
+pub struct PageContext {
+    pub now_us: u64,
+    pub wpm: u16,
+    pub layer: usize,
+    pub layer_name: &'static str,
+    pub locks: KeyboardLeds,
+    pub led_effect_name: &'static str,
+    pub debounce: DebounceConfig,
+    pub usb_configured: bool,
+    /// Local time, if the host has set it
+    pub time: Option<DateTime>,
+}
+
+/// What a page wants after an update or keypress
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PageUpdate {
+    /// Nothing changed
+    Idle,
+    /// The page needs to be rendered again
+    Redraw,
+    /// The page is done, go back to the home page
+    Exit,
+}
+
+pub trait Page {
+    /// Called whenever the page becomes current, before it is rendered
+    fn enter(&mut self, _ctx: &PageContext) {}
+
+    fn handle_keypress(&mut self, _ctx: &PageContext) -> PageUpdate {
+        PageUpdate::Idle
+    }
+
+    /// Called every tick while the page is current
+    fn update(&mut self, ctx: &PageContext) -> PageUpdate;
+
+    /// Draw the page onto a cleared target
+    fn render<D>(&self, ctx: &PageContext, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>;
+}
+
+pub struct Screen {
+    current: PageId,
+    home: PageId,
+    expires_us: Option<u64>,
+}
+
+impl Screen {
+    pub fn new(home: PageId) -> Self {
+        Self {
+            current: home,
+            home,
+            expires_us: None,
+        }
+    }
+
+    pub fn current(&self) -> PageId {
+        self.current
+    }
+
+    /// Make `page` the home page and show it
+    pub fn set_home(&mut self, page: PageId) {
+        self.home = page;
+        self.current = page;
+        self.expires_us = None;
+    }
+
+    /// Show `page` for `duration_us`, then go back to the home page
+    pub fn show_for(&mut self, page: PageId, now_us: u64, duration_us: u64) {
+        self.current = page;
+        self.expires_us = Some(now_us + duration_us);
+    }
+
+    /// Show `page` until it exits or another page is shown
+    pub fn show(&mut self, page: PageId) {
+        self.current = page;
+        self.expires_us = None;
+    }
+
+    pub fn go_home(&mut self) {
+        self.current = self.home;
+        self.expires_us = None;
+    }
+
+    /// Cycle the home page through `PageId::HOME_PAGES`
+    pub fn next_home(&mut self) {
+        let pos = PageId::HOME_PAGES
+            .iter()
+            .position(|p| *p == self.home)
+            .unwrap_or(0);
+        self.set_home(PageId::HOME_PAGES[(pos + 1) % PageId::HOME_PAGES.len()]);
+    }
+
+    /// Go back to the home page if a temporary page has run its time.
+    /// Returns true if the current page changed.
+    pub fn check_expiry(&mut self, now_us: u64) -> bool {
+        match self.expires_us {
+            Some(expires_us) if now_us >= expires_us => {
+                self.go_home();
+                true
+            }
+            _ => false,
+        }
+    }
+}
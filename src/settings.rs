@@ -0,0 +1,307 @@
+//! User settings, persisted to the settings sector of flash.
+//!
+//! The stored layout is a small header followed by a payload of fields in a
+//! fixed order. New fields are appended to the end of the payload, so older
+//! stored settings still load, with any missing fields taking their defaults.
+
+use crate::compositor::{self, Blend, Style, Styles};
+use crate::debounce::{DebounceAlgorithm, DebounceConfig};
+use crate::flash;
+use crate::effects::{self, Params};
+use crate::led_output::{self, Calibration};
+use crate::led_state::{self, LayerColors};
+use smart_leds::RGB8;
+
+const MAGIC: [u8; 4] = *b"CAEK";
+const VERSION: u8 = 1;
+// magic, version, payload length
+const HEADER_LEN: usize = 6;
+const STORED_LEN: usize = flash::PAGE_SIZE as usize;
+
+// Wait for settings to stop changing before writing them, so that stepping
+// through values doesn't wear the flash.
+const SAVE_DELAY_TICKS: u32 = 5000;
+
+// Payload offset just past the layer colors
+const LAYER_COLORS_END: usize = 3 + 4 * led_state::MAX_LAYERS;
+const REACTIVE_EFFECT: usize = LAYER_COLORS_END + 6;
+const LED_STYLES: usize = REACTIVE_EFFECT + 1;
+const LED_TRANSITION: usize = LED_STYLES + 2 * compositor::SLOT_COUNT;
+const EFFECT_PARAMS: usize = LED_TRANSITION + 2;
+// Room for the params of this many effects is kept, so that adding effects
+// doesn't move any fields stored after them
+const STORED_EFFECT_PARAMS: usize = 24;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub debounce: DebounceConfig,
+    /// Index into the effect registry
+    pub led_effect: usize,
+    pub layer_colors: LayerColors,
+    pub brightness: u8,
+    pub calibration: Calibration,
+    /// Most current the LEDs may draw while USB is configured
+    pub led_budget_ma: u16,
+    /// Index into the effect registry of the effect layered over the base
+    /// one, if any
+    pub reactive_effect: Option<usize>,
+    /// Blend mode and opacity of each level of the LED compositor
+    pub led_styles: Styles,
+    /// Crossfade time when the LED effect changes
+    pub led_transition_ms: u16,
+    /// Hue, saturation, value and speed of each LED effect that has them
+    pub effect_params: [Option<Params>; effects::COUNT],
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            debounce: DebounceConfig::default(),
+            led_effect: effects::CHASE_2,
+            layer_colors: led_state::default_layer_colors(),
+            brightness: led_output::MAX_BRIGHTNESS,
+            calibration: Calibration::default(),
+            led_budget_ma: led_output::DEFAULT_CURRENT_BUDGET_MA,
+            reactive_effect: None,
+            led_styles: compositor::default_styles(),
+            led_transition_ms: led_state::DEFAULT_TRANSITION_MS,
+            effect_params: effects::DEFAULT_PARAMS,
+        }
+    }
+}
+
+impl Settings {
+    fn encode(&self, payload: &mut [u8]) -> usize {
+        let fields = [
+            self.debounce.algorithm.as_u8(),
+            self.debounce.threshold,
+            self.led_effect as u8,
+        ];
+        payload[..fields.len()].copy_from_slice(&fields);
+        let mut len = fields.len();
+
+        // Enabled flag then RGB for each layer
+        for color in self.layer_colors.iter() {
+            let bytes = match color {
+                Some(c) => [1, c.r, c.g, c.b],
+                None => [0; 4],
+            };
+            payload[len..len + 4].copy_from_slice(&bytes);
+            len += 4;
+        }
+
+        let fields = [
+            self.brightness,
+            self.calibration.r,
+            self.calibration.g,
+            self.calibration.b,
+        ];
+        payload[len..len + fields.len()].copy_from_slice(&fields);
+        len += fields.len();
+
+        payload[len..len + 2].copy_from_slice(&self.led_budget_ma.to_le_bytes());
+        len += 2;
+
+        // 0xFF for no reactive effect
+        payload[len] = self.reactive_effect.map_or(0xFF, |e| e as u8);
+        len += 1;
+
+        for style in self.led_styles.iter() {
+            payload[len..len + 2].copy_from_slice(&[style.blend.as_u8(), style.opacity]);
+            len += 2;
+        }
+
+        payload[len..len + 2].copy_from_slice(&self.led_transition_ms.to_le_bytes());
+        len += 2;
+
+        for i in 0..STORED_EFFECT_PARAMS {
+            let bytes = match self.effect_params.get(i).copied().flatten() {
+                Some(p) => [p.hue, p.sat, p.val, p.speed],
+                None => [0; 4],
+            };
+            payload[len..len + 4].copy_from_slice(&bytes);
+            len += 4;
+        }
+        len
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let default = Self::default();
+        let field = |i: usize| payload.get(i).copied();
+
+        Self {
+            debounce: DebounceConfig {
+                algorithm: field(0)
+                    .and_then(DebounceAlgorithm::from_u8)
+                    .unwrap_or(default.debounce.algorithm),
+                threshold: field(1)
+                    .filter(|t| {
+                        (crate::debounce::MIN_THRESHOLD..=crate::debounce::MAX_THRESHOLD)
+                            .contains(t)
+                    })
+                    .unwrap_or(default.debounce.threshold),
+            },
+            led_effect: field(2)
+                .map(|e| e as usize)
+                .filter(|e| *e < effects::COUNT)
+                .unwrap_or(default.led_effect),
+            layer_colors: if payload.len() >= LAYER_COLORS_END {
+                let mut colors = [None; led_state::MAX_LAYERS];
+                for (i, color) in colors.iter_mut().enumerate() {
+                    let bytes = &payload[3 + 4 * i..3 + 4 * (i + 1)];
+                    if bytes[0] == 1 {
+                        *color = Some(RGB8 {
+                            r: bytes[1],
+                            g: bytes[2],
+                            b: bytes[3],
+                        });
+                    }
+                }
+                colors
+            } else {
+                default.layer_colors
+            },
+            brightness: field(LAYER_COLORS_END)
+                .filter(|b| *b >= led_output::MIN_BRIGHTNESS)
+                .unwrap_or(default.brightness),
+            calibration: match (
+                field(LAYER_COLORS_END + 1),
+                field(LAYER_COLORS_END + 2),
+                field(LAYER_COLORS_END + 3),
+            ) {
+                (Some(r), Some(g), Some(b)) => Calibration { r, g, b },
+                _ => default.calibration,
+            },
+            led_budget_ma: match (field(LAYER_COLORS_END + 4), field(LAYER_COLORS_END + 5)) {
+                (Some(lo), Some(hi)) => Some(u16::from_le_bytes([lo, hi])),
+                _ => None,
+            }
+            .filter(|ma| *ma <= led_output::MAX_CURRENT_BUDGET_MA)
+            .unwrap_or(default.led_budget_ma),
+            reactive_effect: match field(REACTIVE_EFFECT) {
+                Some(e) if (e as usize) < effects::COUNT => Some(e as usize),
+                _ => None,
+            },
+            led_styles: {
+                let mut styles = default.led_styles;
+                for (i, style) in styles.iter_mut().enumerate() {
+                    let blend = field(LED_STYLES + 2 * i).and_then(Blend::from_u8);
+                    if let (Some(blend), Some(opacity)) = (blend, field(LED_STYLES + 2 * i + 1)) {
+                        *style = Style { blend, opacity };
+                    }
+                }
+                styles
+            },
+            led_transition_ms: match (field(LED_TRANSITION), field(LED_TRANSITION + 1)) {
+                (Some(lo), Some(hi)) => Some(u16::from_le_bytes([lo, hi])),
+                _ => None,
+            }
+            .filter(|ms| *ms <= led_state::MAX_TRANSITION_MS)
+            .unwrap_or(default.led_transition_ms),
+            effect_params: {
+                let mut params = default.effect_params;
+                // Only the effects that have params, others stay `None`
+                for (i, p) in params.iter_mut().enumerate().take(STORED_EFFECT_PARAMS) {
+                    let offset = EFFECT_PARAMS + 4 * i;
+                    if let (Some(p), Some(bytes)) = (p, payload.get(offset..offset + 4)) {
+                        *p = Params {
+                            hue: bytes[0],
+                            sat: bytes[1],
+                            val: bytes[2],
+                            speed: bytes[3],
+                        };
+                    }
+                }
+                params
+            },
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+pub struct SettingsStore {
+    settings: Settings,
+    saved: Settings,
+    ticks_since_change: u32,
+}
+
+impl SettingsStore {
+    /// Load settings from flash, falling back to defaults if nothing valid is
+    /// stored.
+    pub fn load() -> Self {
+        let settings = Self::read().unwrap_or_default();
+
+        Self {
+            settings,
+            saved: settings,
+            ticks_since_change: 0,
+        }
+    }
+
+    fn read() -> Option<Settings> {
+        let stored = flash::read(flash::SETTINGS_OFFSET, STORED_LEN);
+        if stored[..4] != MAGIC || stored[4] != VERSION {
+            return None;
+        }
+
+        let len = stored[5] as usize;
+        if HEADER_LEN + len + 1 > STORED_LEN {
+            return None;
+        }
+        let payload = &stored[HEADER_LEN..HEADER_LEN + len];
+        if checksum(payload) != stored[HEADER_LEN + len] {
+            return None;
+        }
+
+        Some(Settings::decode(payload))
+    }
+
+    fn write(&self) {
+        // Unused bytes are left as erased flash
+        let mut stored = [0xFFu8; STORED_LEN];
+        stored[..4].copy_from_slice(&MAGIC);
+        stored[4] = VERSION;
+        let len = self.settings.encode(&mut stored[HEADER_LEN..]);
+        stored[5] = len as u8;
+        stored[HEADER_LEN + len] = checksum(&stored[HEADER_LEN..HEADER_LEN + len]);
+
+        flash::write_sector(flash::SETTINGS_OFFSET, &stored);
+    }
+
+    pub fn get(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Modify the settings. They're due to be saved once they've been left
+    /// alone for a few seconds.
+    pub fn update<F: FnOnce(&mut Settings)>(&mut self, f: F) {
+        f(&mut self.settings);
+        self.ticks_since_change = 0;
+    }
+
+    /// Called once per scan. Returns true while there are changes due to be
+    /// written with `save`.
+    pub fn tick(&mut self) -> bool {
+        if self.settings == self.saved {
+            return false;
+        }
+
+        self.ticks_since_change = self.ticks_since_change.saturating_add(1);
+        return self.ticks_since_change >= SAVE_DELAY_TICKS;
+    }
+
+    /// Write the settings to flash, if they changed since the last save. This
+    /// blocks for tens of milliseconds, so it's run from its own task rather
+    /// than the scan.
+    pub fn save(&mut self) {
+        if self.settings == self.saved {
+            return;
+        }
+
+        self.write();
+        self.saved = self.settings;
+    }
+}
@@ -0,0 +1,104 @@
+//! Idle sleep with wake-on-keypress.
+//!
+//! After a period without any key activity the matrix rows are all driven low
+//! and every column is armed for a falling edge GPIO interrupt. The scan alarm
+//! is stopped while asleep, so the core sits in WFI until a key is pressed (or
+//! USB needs servicing). The first press fires IO_IRQ_BANK0, which disarms the
+//! columns and restarts scanning. As the key is still held when the scan
+//! resumes, the press goes through the debouncer as normal and is not lost.
+
+use rp_pico::pac;
+
+pub struct IdleSleep<const NUM_COLUMNS: usize> {
+    column_gpios: [u8; NUM_COLUMNS],
+    timeout_ticks: u32,
+    idle_ticks: u32,
+    asleep: bool,
+}
+
+impl<const NUM_COLUMNS: usize> IdleSleep<NUM_COLUMNS> {
+    /// `column_gpios` are the GPIO numbers of the matrix columns, used to arm
+    /// the wake interrupts. A `timeout_ticks` of 0 disables idle sleep.
+    pub fn new(column_gpios: [u8; NUM_COLUMNS], timeout_ticks: u32) -> Self {
+        Self {
+            column_gpios,
+            timeout_ticks,
+            idle_ticks: 0,
+            asleep: false,
+        }
+    }
+
+    /// Any key activity (press or release) resets the idle timer.
+    pub fn handle_keypress(&mut self) {
+        self.idle_ticks = 0;
+    }
+
+    /// Called once per scan. Returns true once the keyboard has been idle for
+    /// the configured timeout and should be put to sleep.
+    pub fn tick(&mut self) -> bool {
+        if self.timeout_ticks == 0 || self.asleep {
+            return false;
+        }
+
+        self.idle_ticks = self.idle_ticks.saturating_add(1);
+        self.idle_ticks >= self.timeout_ticks
+    }
+
+    /// Arm the column edge interrupts. The caller is responsible for driving
+    /// the rows low beforehand, and for stopping the scan alarm.
+    pub fn sleep(&mut self) {
+        for gpio in self.column_gpios.iter() {
+            clear_edge_low(*gpio);
+            set_edge_low_enabled(*gpio, true);
+        }
+        self.asleep = true;
+    }
+
+    /// Disarm the column interrupts. Returns false if we weren't asleep, in
+    /// which case the interrupt was spurious and there is nothing to restore.
+    pub fn wake(&mut self) -> bool {
+        for gpio in self.column_gpios.iter() {
+            set_edge_low_enabled(*gpio, false);
+            clear_edge_low(*gpio);
+        }
+
+        let was_asleep = self.asleep;
+        self.asleep = false;
+        self.idle_ticks = 0;
+        return was_asleep;
+    }
+}
+
+// Each INTE/INTR register covers 8 GPIOs, with 4 bits per GPIO:
+// LEVEL_LOW, LEVEL_HIGH, EDGE_LOW, EDGE_HIGH.
+fn edge_low_mask(gpio: u8) -> u32 {
+    1 << (4 * (gpio as u32 % 8) + 2)
+}
+
+fn set_edge_low_enabled(gpio: u8, enabled: bool) {
+    // Safety: the pins themselves are owned by the matrix, we only touch the
+    // proc0 interrupt enable bits for the column pins here.
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    let mask = edge_low_mask(gpio);
+    let update = |bits: u32| if enabled { bits | mask } else { bits & !mask };
+
+    match gpio / 8 {
+        0 => io.proc0_inte0.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        1 => io.proc0_inte1.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        2 => io.proc0_inte2.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+        _ => io.proc0_inte3.modify(|r, w| unsafe { w.bits(update(r.bits())) }),
+    }
+}
+
+fn clear_edge_low(gpio: u8) {
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    let mask = edge_low_mask(gpio);
+
+    // Edge bits in INTR are write-1-to-clear
+    match gpio / 8 {
+        0 => io.intr0.write(|w| unsafe { w.bits(mask) }),
+        1 => io.intr1.write(|w| unsafe { w.bits(mask) }),
+        2 => io.intr2.write(|w| unsafe { w.bits(mask) }),
+        _ => io.intr3.write(|w| unsafe { w.bits(mask) }),
+    }
+}
@@ -43,6 +43,19 @@ where
         }
         Ok(())
     }
+    /// Drive every row low at once, so that any pressed key pulls its column
+    /// low. Used while sleeping to wake on a column edge interrupt; call
+    /// `clear` to go back to normal scanning.
+    pub fn drive_all_low<E>(&mut self) -> Result<(), E>
+    where
+        C: InputPin<Error = E>,
+        R: OutputPin<Error = E>,
+    {
+        for r in self.rows.iter_mut() {
+            r.set_low()?;
+        }
+        Ok(())
+    }
     pub fn get<E>(&mut self) -> Result<PressedKeys<CS, RS>, E>
     where
         C: InputPin<Error = E>,
@@ -0,0 +1,45 @@
+//! Rolling words-per-minute estimate.
+//!
+//! Keeps the timestamps of recent debounced presses and counts how many fall
+//! within the last `WINDOW_US`, using the usual 5 keystrokes per word.
+
+const WINDOW_US: u64 = 5_000_000;
+const KEYSTROKES_PER_WORD: u64 = 5;
+
+pub struct WpmTracker<const N: usize> {
+    presses: [u64; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> WpmTracker<N> {
+    pub fn new() -> Self {
+        Self {
+            presses: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Record a press at `now_us`. If more than N presses land within the
+    /// window, the oldest are dropped, capping the estimate.
+    pub fn handle_keypress(&mut self, now_us: u64) {
+        self.presses[self.head] = now_us;
+        self.head = (self.head + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// Current estimate, 0 once nothing has been typed for a whole window.
+    pub fn wpm(&mut self, now_us: u64) -> u16 {
+        while self.len > 0 {
+            let oldest = self.presses[(self.head + N - self.len) % N];
+            if now_us.saturating_sub(oldest) <= WINDOW_US {
+                break;
+            }
+            self.len -= 1;
+        }
+
+        let per_minute = self.len as u64 * 60_000_000 / WINDOW_US;
+        return (per_minute / KEYSTROKES_PER_WORD) as u16;
+    }
+}
@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+"""Push notifications to the caekbd OLED over the USB serial host channel.
+
+Examples:
+
+    caekbd-notify.py "build failed" --priority 8
+    caekbd-notify.py "3 unread" --id 2 --icon cap_on --priority 0
+    caekbd-notify.py "Song - Artist" --id 3 --progress 40 --expire 30
+    caekbd-notify.py --clear 3
+    caekbd-notify.py --clear
+
+Needs pyserial (`pip install pyserial`).
+"""
+
+import argparse
+
+import caekbd_host
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("text", nargs="?", help="text to show")
+    parser.add_argument("--port", help="serial port, found by USB id if not given")
+    parser.add_argument("--id", type=int, default=0, help="sending the same id again replaces the notification")
+    parser.add_argument("--priority", type=int, default=3, choices=range(10), metavar="0-9",
+                        help="0 doesn't pop up, 7 and up stay until a keypress")
+    parser.add_argument("--expire", type=int, default=0, metavar="SECONDS", help="0 never expires")
+    parser.add_argument("--icon", help="name of an image in images/, e.g. cap_on")
+    parser.add_argument("--progress", type=int, metavar="PERCENT")
+    parser.add_argument("--clear", nargs="?", const="all", metavar="ID",
+                        help="remove the notification with ID, or all of them")
+    args = parser.parse_args()
+
+    if args.clear is not None:
+        line = "notify clear" if args.clear == "all" else f"notify clear {int(args.clear)}"
+    elif args.text is not None:
+        words = ["notify", str(args.id), str(args.priority), str(args.expire)]
+        if args.icon:
+            words.append(f"icon={args.icon}")
+        if args.progress is not None:
+            words.append(f"progress={args.progress}")
+        words.append(" ".join(args.text.split()))
+        line = " ".join(words)
+    else:
+        parser.error("give some text or --clear")
+
+    with caekbd_host.open_port(args.port) as port:
+        caekbd_host.command(port, line)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,26 @@
+#!/usr/bin/env python3
+"""Set the caekbd clock to the host's local time.
+
+Run it at login, or from cron every so often to correct any drift. Needs
+pyserial (`pip install pyserial`).
+"""
+
+import argparse
+import time
+
+import caekbd_host
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("--port", help="serial port, found by USB id if not given")
+    args = parser.parse_args()
+
+    with caekbd_host.open_port(args.port) as port:
+        now = time.time()
+        offset_mins = round(time.localtime(now).tm_gmtoff / 60)
+        caekbd_host.command(port, f"time {int(now)} {offset_mins}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,54 @@
+#!/usr/bin/env python3
+"""Report the host's volume level to caekbd, for the media key overlay.
+
+Without it the overlay only shows an estimate. Either pass a level, or use
+--pactl to follow the default PulseAudio/PipeWire sink. Needs pyserial
+(`pip install pyserial`).
+
+    caekbd-volume.py 40
+    caekbd-volume.py --pactl
+"""
+
+import argparse
+import re
+import subprocess
+import time
+
+import caekbd_host
+
+POLL_SECS = 0.2
+
+
+def pactl_volume():
+    out = subprocess.run(["pactl", "get-sink-volume", "@DEFAULT_SINK@"],
+                         capture_output=True, text=True, check=True).stdout
+    match = re.search(r"(\d+)%", out)
+    return min(int(match.group(1)), 100) if match else None
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("level", nargs="?", type=int, help="volume in percent")
+    parser.add_argument("--port", help="serial port, found by USB id if not given")
+    parser.add_argument("--pactl", action="store_true", help="keep following the default sink's volume")
+    args = parser.parse_args()
+
+    if args.level is None and not args.pactl:
+        parser.error("give a level or --pactl")
+
+    with caekbd_host.open_port(args.port) as port:
+        if not args.pactl:
+            caekbd_host.command(port, f"volume {max(0, min(args.level, 100))}")
+            return
+
+        sent = None
+        while True:
+            level = pactl_volume()
+            if level is not None and level != sent:
+                caekbd_host.command(port, f"volume {level}")
+                sent = level
+            time.sleep(POLL_SECS)
+
+
+if __name__ == "__main__":
+    main()
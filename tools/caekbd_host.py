@@ -0,0 +1,37 @@
+"""Shared helpers for the caekbd host tools, which talk to the keyboard over
+its USB serial host channel."""
+
+import sys
+
+import serial
+from serial.tools import list_ports
+
+# keyberon's VID/PID, used by the firmware
+VID = 0x16C0
+PID = 0x27DB
+
+
+def find_port():
+    for port in list_ports.comports():
+        if port.vid == VID and port.pid == PID:
+            return port.device
+    sys.exit("caekbd not found, pass --port")
+
+
+def open_port(device=None):
+    """Open the serial port at `device`, or find the keyboard by USB id."""
+    return serial.Serial(device or find_port(), timeout=1)
+
+
+def command(port, line):
+    """Send one command and wait for its reply. Exits on an error reply."""
+    port.write(line.encode() + b"\n")
+    # Skip any unrelated output, like chatter reports
+    while True:
+        reply = port.readline().decode(errors="replace").strip()
+        if not reply:
+            sys.exit("no reply from caekbd")
+        if reply == "ok":
+            return
+        if reply.startswith("err"):
+            sys.exit(reply)